@@ -1,72 +1,421 @@
 #!/usr/bin/python3
 
-import astral  # type: ignore
-import astral.geocoder  # type: ignore
-import astral.sun  # type: ignore
-
 import argparse
-import datetime
-import os
-import pathlib
-import pytz
-import re
+import dataclasses
 import sys
+from typing import List, Optional
+
+from timelapse import activity
+from timelapse import cli
+from timelapse import detection
+from timelapse import duplicates
+from timelapse import frames
+from timelapse import index
+from timelapse import location
+from timelapse import quality
+from timelapse import schedule
+from timelapse import selection
 
 
 # Simple arg namespace so we get typing of our arguments.  Awkward but
 # adds type safety.
 class ArgNamespace:
-    basedir: str
-    skip_weekends: bool
+    basedirs: List[str]
+    skip_weekends: Optional[bool]
     sample: int
+    schedule: List[str]
+    city: Optional[str]
+    latitude: Optional[float]
+    longitude: Optional[float]
+    elevation: float
+    timezone: Optional[str]
+    config: Optional[str]
+    camera: Optional[str]
+    start: Optional[str]
+    end: Optional[str]
+    between: Optional[str]
+    weekdays: Optional[str]
+    dates: Optional[str]
+    every: Optional[str]
+    per_day: Optional[int]
+    video_length: Optional[float]
+    fps: float
+    daily: Optional[str]
+    daily_tolerance: str
+    reject_bad: bool
+    min_brightness: float
+    min_contrast: float
+    max_repeated_rows: float
+    max_blockiness: float
+    min_sharpness: float
+    collapse_duplicates: bool
+    duplicate_threshold: int
+    heartbeat: Optional[str]
+    activity: bool
+    idle_weight: float
+    detect_model: Optional[str]
+    detect_labels: Optional[str]
+    with_objects: List[str]
+    without_objects: List[str]
+    min_confidence: float
+    use_index: bool
+    filename_layout: Optional[str]
+
+
+# Quality scores for a frame, from the pile's index if they've been
+# worked out before, and saved there if not.
+def frame_quality(indexes: index.Indexes, path: str) -> quality.Scores:
+    cached = indexes.scores(path)
+    names = [field.name for field in dataclasses.fields(quality.Scores)]
+    if all(name in cached for name in names):
+        return quality.Scores(**{name: cached[name] for name in names})
+    scores = quality.analyze(path)
+    indexes.set_scores(path, dataclasses.asdict(scores))
+    return scores
+
+
+# The same for a frame's perceptual hash.  SQLite integers are signed,
+# so hashes are stored as signed 64 bit numbers.
+def frame_hash(indexes: index.Indexes, path: str) -> int:
+    cached = indexes.scores(path).get("difference_hash")
+    if isinstance(cached, int):
+        return cached % (1 << 64)
+    bits = duplicates.difference_hash(path)
+    signed = bits - (1 << 64) if bits >= 1 << 63 else bits
+    indexes.set_scores(path, {"difference_hash": signed})
+    return bits
 
 
 def main() -> None:
     parser = argparse.ArgumentParser(
         description="Grab timelapse frames from an RTSP source"
     )
-    parser.add_argument("basedir")
+    parser.add_argument(
+        "basedirs",
+        nargs="+",
+        help="one or more piles of frames, merged into a single timeline",
+    )
     parser.add_argument(
         "--skip-weekends",
         type=bool,
+        default=None,
+        action=argparse.BooleanOptionalAction,
+        help="defaults to on, unless --schedule is given",
+    )
+    parser.add_argument(
+        "--schedule",
+        action="append",
+        default=[],
+        help="keep frames this rule allows, e.g. 'mon..sat sunrise..15:30' (repeatable; replaces the default 'dawn..dusk')",
+    )
+    parser.add_argument(
+        "--from",
+        dest="start",
+        type=str,
+        help="only frames captured at or after this date or date and time",
+    )
+    parser.add_argument(
+        "--to",
+        dest="end",
+        type=str,
+        help="only frames captured before this date and time (or by the end of this date)",
+    )
+    parser.add_argument(
+        "--between",
+        type=str,
+        help="only frames in this daily window, e.g. 07:00..15:30 or sunrise+1h..noon",
+    )
+    parser.add_argument(
+        "--weekdays",
+        type=str,
+        help="only frames on these days, e.g. mon..fri or mon,wed,fri",
+    )
+    parser.add_argument(
+        "--dates",
+        type=str,
+        help="only frames on these dates, e.g. 2021-03-01,2021-03-08..2021-03-12",
+    )
+    sampling = parser.add_mutually_exclusive_group()
+    sampling.add_argument(
+        "--sample", type=int, default=1, help="keep every Nth matching frame"
+    )
+    sampling.add_argument(
+        "--every",
+        type=str,
+        help="keep the frame nearest each multiple of this much time, e.g. 5m",
+    )
+    sampling.add_argument(
+        "--per-day", type=int, help="keep this many evenly spread frames per day"
+    )
+    sampling.add_argument(
+        "--video-length",
+        type=float,
+        help="keep just enough evenly spread frames for a video this many seconds long at --fps",
+    )
+    sampling.add_argument(
+        "--daily",
+        type=str,
+        help="keep one frame per day with the sun in the same place: noon, sunrise+2h, elevation:30[:setting] or azimuth:180",
+    )
+    parser.add_argument("--fps", type=float, default=30, help="for --video-length")
+    parser.add_argument(
+        "--activity",
+        action="store_true",
+        help="for --video-length, spend more frames where more is changing",
+    )
+    parser.add_argument(
+        "--idle-weight",
+        type=float,
+        default=0.2,
+        help="for --activity, how much time with nothing changing counts, 0 to 1 (default: 0.2)",
+    )
+    parser.add_argument(
+        "--daily-tolerance",
+        type=str,
+        default="30m",
+        help="for --daily, skip days with no frame this close to the target",
+    )
+    parser.add_argument(
+        "--reject-bad",
+        action="store_true",
+        help="drop dark, blank, smeared, blocky or blurry frames, saying why on stderr",
+    )
+    defaults = quality.Thresholds()
+    parser.add_argument(
+        "--min-brightness",
+        type=float,
+        default=defaults.min_brightness,
+        help=f"for --reject-bad, mean brightness 0-255 (default: {defaults.min_brightness})",
+    )
+    parser.add_argument(
+        "--min-contrast",
+        type=float,
+        default=defaults.min_contrast,
+        help=f"for --reject-bad, brightness standard deviation (default: {defaults.min_contrast})",
+    )
+    parser.add_argument(
+        "--max-repeated-rows",
+        type=float,
+        default=defaults.max_repeated_rows,
+        help=f"for --reject-bad, fraction of rows repeating the one above (default: {defaults.max_repeated_rows})",
+    )
+    parser.add_argument(
+        "--max-blockiness",
+        type=float,
+        default=defaults.max_blockiness,
+        help=f"for --reject-bad, compression block edge strength (default: {defaults.max_blockiness})",
+    )
+    parser.add_argument(
+        "--min-sharpness",
+        type=float,
+        default=defaults.min_sharpness,
+        help=f"for --reject-bad, variance of the Laplacian (default: {defaults.min_sharpness})",
+    )
+    parser.add_argument(
+        "--collapse-duplicates",
+        action="store_true",
+        help="drop frames that look the same as the last one kept",
+    )
+    parser.add_argument(
+        "--duplicate-threshold",
+        type=int,
+        default=4,
+        help="for --collapse-duplicates, how many of 64 hash bits may differ (default: 4)",
+    )
+    parser.add_argument(
+        "--heartbeat",
+        type=str,
+        help="for --collapse-duplicates, keep a frame at least this often anyway, e.g. 10m",
+    )
+    parser.add_argument(
+        "--detect-model",
+        type=str,
+        help="ONNX object detection model (YOLO style) for --with and --without",
+    )
+    parser.add_argument(
+        "--detect-labels",
+        type=str,
+        help="class names for --detect-model, one per line (default: COCO)",
+    )
+    parser.add_argument(
+        "--with",
+        dest="with_objects",
+        action="append",
+        default=[],
+        help="keep only frames with one of these detected, as LABEL or LABEL:CONFIDENCE, e.g. person (repeatable)",
+    )
+    parser.add_argument(
+        "--without",
+        dest="without_objects",
+        action="append",
+        default=[],
+        help="drop frames with this detected, as LABEL or LABEL:CONFIDENCE, e.g. dog:0.7 (repeatable)",
+    )
+    parser.add_argument(
+        "--min-confidence",
+        type=float,
+        default=0.5,
+        help="for --with and --without, the confidence when none is given (default: 0.5)",
+    )
+    parser.add_argument(
+        "--use-index",
+        type=bool,
         default=True,
         action=argparse.BooleanOptionalAction,
+        help="read piles from their frames.sqlite index where they have one (see manage-timelapse.py reindex)",
     )
-    parser.add_argument("--sample", type=int, default=1)
+    cli.add_location_arguments(parser)
+    cli.add_layout_argument(parser)
     args = parser.parse_args(namespace=ArgNamespace)
+    if (args.with_objects or args.without_objects) and not args.detect_model:
+        parser.error("--with and --without need --detect-model")
+    if args.activity and args.video_length is None:
+        parser.error("--activity needs --video-length")
+    if not 0 <= args.idle_weight <= 1:
+        parser.error("--idle-weight must be 0 to 1")
+    if args.sample < 1:
+        parser.error("--sample must be at least 1")
 
-    # Look for files with name components YYYY-MM-DD_HHMMSS
-    filename_regex = re.compile(r"\D(\d\d\d\d)-(\d\d)-(\d\d)_(\d\d)(\d\d)(\d\d)\D")
-
-    # Use Astral to lookup dawn and dusk for the dates of files we
-    # find, relative to a specific city.
-    astral_db = astral.geocoder.database()
-    camera_city = astral.geocoder.lookup("Seattle", astral_db)
-    timezone = pytz.timezone(camera_city.timezone)
-
-    seen_count = 0  # for sampling
-    # Walk!
-    for root, dirs, files in os.walk(args.basedir):
-        for filename in files:
-            # Skip names that don't match our patterh
-            match = filename_regex.search(filename)
-            if match is None:
-                continue
-
-            # Localize the time in the filename string
-            yy, mm, dd, h, m, s = (int(x) for x in match.groups())
-            image_datetime = datetime.datetime(yy, mm, dd, h, m, s, tzinfo=timezone)
-
-            # Skip weekends if requested.
-            if args.skip_weekends and image_datetime.weekday() >= 5:
-                continue
+    # Dawn and dusk (and other sun events), and which day of the week
+    # it is, depend on where the camera is.
+    rules = args.schedule or ["dawn..dusk"]
+    if args.skip_weekends or (args.skip_weekends is None and not args.schedule):
+        rules.append("exclude sat..sun")
+    # The narrower --between/--weekdays/--dates selection has to match
+    # on top of the schedule, so it's one rule of its own.
+    terms = [term for term in (args.between, args.weekdays, args.dates) if term]
+    # Frame indexes, for listing piles and keeping what's worked out
+    # about each frame.
+    indexes = index.Indexes()
+    try:
+        camera_location = cli.find_location(args, args.basedirs[0])
+        timezone = camera_location.tzinfo
+        layouts = {basedir: cli.find_layout(args, basedir) for basedir in args.basedirs}
+        frame_schedule = schedule.Schedule.parse(rules, camera_location)
+        narrowed = schedule.Schedule.parse([" ".join(terms)] if terms else [], camera_location)
+        start = args.start and selection.parse_bound(args.start, timezone, upper=False)
+        end = args.end and selection.parse_bound(args.end, timezone, upper=True)
+        spacing = args.every and selection.parse_duration(args.every)
+        daily = args.daily and selection.solar_target(args.daily, camera_location)
+        tolerance = selection.parse_duration(args.daily_tolerance)
+        heartbeat = args.heartbeat and selection.parse_duration(args.heartbeat)
+        wanted = [
+            detection.parse_condition(text, args.min_confidence) for text in args.with_objects
+        ]
+        unwanted = [
+            detection.parse_condition(text, args.min_confidence)
+            for text in args.without_objects
+        ]
+        detections = None
+        if wanted or unwanted:
+            detector = detection.Detector(args.detect_model, args.detect_labels)  # type: ignore
+            detection.check_conditions(detector, wanted + unwanted)
+            detections = detection.DetectionCache(detector, indexes)
+    except (
+        detection.DetectionError,
+        frames.LayoutError,
+        location.LocationError,
+        schedule.ScheduleError,
+        selection.SelectionError,
+    ) as ex:
+        parser.error(str(ex))
 
-            # Only print filenames when the sun is up and that meet our sampling requirement.
-            sun_info = astral.sun.sun(camera_city.observer, date=image_datetime, tzinfo=timezone)
-            if sun_info["dawn"] <= image_datetime <= sun_info["dusk"]:
-                if seen_count % args.sample == 0:
-                    print(os.path.join(root, filename))
-                seen_count += 1
+    # Walk (or read the index of) each pile!  Frames come back sorted by
+    # capture time, so the output can go straight to ffmpeg.
+    try:
+        if args.use_index:
+            listed = index.scan(args.basedirs, timezone, start, end, indexes, layouts)
+        else:
+            listed = frames.scan(args.basedirs, timezone, layouts)
+    except index.FrameIndexError as ex:
+        parser.error(str(ex))
+    found = selection.in_range(listed, start, end)
+    # Piles can be shared by cameras whose frames are told apart by
+    # name; with --camera, only that camera's are wanted.
+    if args.camera is not None:
+        found = [frame for frame in found if frame.camera in (None, args.camera)]
+    # Only keep frames the schedule allows (by default, weekdays while
+    # the sun is up) and that are in any narrower selection...
+    found = [
+        frame
+        for frame in found
+        if frame_schedule.contains(frame.time) and narrowed.contains(frame.time)
+    ]
+    # ...and, if asked, that look all right...
+    if args.reject_bad:
+        thresholds = quality.Thresholds(
+            min_brightness=args.min_brightness,
+            min_contrast=args.min_contrast,
+            max_repeated_rows=args.max_repeated_rows,
+            max_blockiness=args.max_blockiness,
+            min_sharpness=args.min_sharpness,
+        )
+        good = []
+        for frame in found:
+            try:
+                problems = thresholds.problems(frame_quality(indexes, frame.path))
+            except OSError as ex:
+                problems = [f"can't read ({ex})"]
+            if problems:
+                print(f"Dropping {frame.path}: {', '.join(problems)}", file=sys.stderr)
+            else:
+                good.append(frame)
+        found = good
+    # ...and aren't the same as the frame before...
+    if args.collapse_duplicates:
+        hashed = []
+        hashes = []
+        for frame in found:
+            try:
+                hashes.append(frame_hash(indexes, frame.path))
+                hashed.append(frame)
+            except OSError as ex:
+                print(f"Dropping {frame.path}: can't read ({ex})", file=sys.stderr)
+        found, collapsed = duplicates.collapse(
+            hashed, hashes, args.duplicate_threshold, heartbeat or None
+        )
+        print(f"Collapsed {collapsed} near-duplicate frames", file=sys.stderr)
+    # ...and have (or don't have) the right things in them...
+    if detections is not None:
+        kept = []
+        try:
+            for frame in found:
+                try:
+                    objects = detections.detections(frame.path)
+                except OSError as ex:
+                    print(f"Dropping {frame.path}: can't read ({ex})", file=sys.stderr)
+                    continue
+                if wanted and not any(detection.matches(objects, c) for c in wanted):
+                    continue
+                if any(detection.matches(objects, c) for c in unwanted):
+                    continue
+                kept.append(frame)
+        finally:
+            detections.save()
+        print(f"Dropped {len(found) - len(kept)} frames by what's in them", file=sys.stderr)
+        found = kept
+    # ...then sample what's left.
+    if spacing:
+        found = selection.sample_spacing(found, spacing, timezone)
+    elif args.per_day is not None:
+        found = selection.sample_per_day(found, args.per_day, timezone)
+    elif args.video_length is not None and args.activity:
+        found = selection.sample_activity(
+            found,
+            activity.scores(found, indexes),
+            round(args.video_length * args.fps),
+            args.idle_weight,
+        )
+    elif args.video_length is not None:
+        found = selection.sample_count(found, round(args.video_length * args.fps))
+    elif daily:
+        found, skipped = selection.sample_daily(found, camera_location, daily, tolerance)
+        for day in skipped:
+            print(f"Skipping {day}: no frame near {args.daily}", file=sys.stderr)
+    else:
+        found = selection.sample_every_nth(found, args.sample)
+    for frame in found:
+        print(frame.path)
 
 
 if __name__ == "__main__":
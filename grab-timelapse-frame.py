@@ -1,61 +1,217 @@
 #!/usr/bin/python3
 
-import astral  # type: ignore
-import astral.geocoder  # type: ignore
-import astral.sun  # type: ignore
-
 import argparse
 import datetime
 import pathlib
-import pytz
+import sqlite3
 import subprocess
 import sys
+import threading
 import time
-from typing import Tuple
-
+from typing import List, Optional
 
-# Check if the sun is currently "out" in the specified city based on
-# today's dawn and dusk in that city.
-def sun_is_out(city: str, buffer_minutes: int) -> bool:
-    astral_db = astral.geocoder.database()
-    camera_city = astral.geocoder.lookup(city, astral_db)
-    timezone = pytz.timezone(camera_city.timezone)
-    sun_info = astral.sun.sun(camera_city.observer, tzinfo=timezone)
-    delta = datetime.timedelta(minutes=buffer_minutes)
-
-    now = datetime.datetime.now(tz=timezone)
-    lower = sun_info["dawn"] - delta
-    upper = sun_info["dusk"] + delta
-    return lower <= now <= upper
+from timelapse import capture
+from timelapse import config
+from timelapse import frames
+from timelapse import index
+from timelapse import location
+from timelapse import metadata
+from timelapse import privacy
+from timelapse import schedule
 
 
 # Simple arg namespace so we get typing of our arguments.  Awkward but
 # adds type safety.
 class ArgNamespace:
-    output_directory: str
+    output_directory: Optional[str]
     output_filenames: str
-    url: str
+    name: Optional[str]
+    url: Optional[str]
     interval: int
-    duration: int
+    duration: Optional[int]
     daylight_only: bool
     daylight_buffer_minutes: int
     city: str
+    latitude: Optional[float]
+    longitude: Optional[float]
+    elevation: float
+    timezone: Optional[str]
+    daemon: bool
+    max_frame_age: Optional[float]
+    backend: str
+    privacy_masks: List[str]
+    privacy_mode: str
+    mask_at_capture: bool
+    frame_index: bool
+    config: Optional[str]
+    schedule: List[str]
+
+
+def write_metadata(camera: config.CameraConfig) -> None:
+    try:
+        camera.write_metadata()
+    except OSError as ex:
+        print(f"{camera.name}: couldn't write metadata: {ex}")
+
+
+# The camera's frame index, if it keeps one, there is one (see
+# `manage-timelapse.py reindex`) and it can be opened.
+def open_index(camera: config.CameraConfig) -> Optional[index.FrameIndex]:
+    base = metadata.base_directory(camera.output_directory)
+    if not camera.frame_index or not (base / index.FILENAME).is_file():
+        return None
+    try:
+        return index.FrameIndex(base)
+    except index.FrameIndexError as ex:
+        print(f"{camera.name}: not indexing frames: {ex}")
+        return None
+
+
+# Record a newly written frame in the index.  A frame that can't be
+# indexed is still captured; `manage-timelapse.py reindex` can catch up.
+def index_frame(
+    camera: config.CameraConfig, frame_index: Optional[index.FrameIndex], output: pathlib.Path
+) -> None:
+    if frame_index is None:
+        return
+    parsed = frames.read_name(output.name, camera.location().tzinfo, None, camera.layout())
+    if parsed is None:
+        return
+    try:
+        frame_index.add(str(output), parsed[0], camera.name)
+    except (OSError, sqlite3.Error) as ex:
+        print(f"{camera.name}: couldn't index {output}: {ex}")
+
+
+# Write a frame, first hiding the camera's privacy masks in it if
+# they're to be applied at capture time (see timelapse/privacy.py), so
+# the unmasked frame never reaches the disk.
+def save_frame(camera: config.CameraConfig, output: pathlib.Path, frame: bytes) -> None:
+    if camera.mask_at_capture and camera.privacy_masks:
+        polygons = [privacy.parse_polygon(mask) for mask in camera.privacy_masks]
+        frame = privacy.hide_in_frame(
+            frame, polygons, camera.privacy_mode, capture.image_format(output)
+        )
+    capture.write_frame(output, frame)
+
+
+# Long-running mode: keep the RTSP session open and write the latest
+# decoded frame on every tick.  Ticks are scheduled against a fixed
+# start time so frames don't drift; ticks that are missed entirely are
+# skipped rather than bunched up.
+def run_daemon(camera: config.CameraConfig, duration: Optional[int]) -> None:
+    stream: capture.FrameStream
+    if camera.backend == "ffmpeg":
+        # Decode at least once a second (and at least twice per tick)
+        # so the latest frame is close to on time, without decoding
+        # every frame the camera sends.
+        stream = capture.FfmpegFrameStream(
+            camera.url, camera.name, frame_rate=max(1.0, 2.0 / camera.interval)
+        )
+    else:
+        fmt = capture.image_format(pathlib.Path(camera.filename_template()))
+        stream = capture.NativeFrameStream(camera.url, camera.name, fmt)
+    max_age = camera.max_frame_age or max(2.0, float(camera.interval))
+    capture_schedule = camera.capture_schedule()
+    write_metadata(camera)
+    frame_index = open_index(camera)
+    start = time.time()
+    end_time = start + duration if duration else None
+    # Open the session now so there is a frame ready by the first tick.
+    stream.start()
+
+    failed = 0
+    succeeded = 0
+    tick = 0
+    while end_time is None or time.time() < end_time:
+        tick = max(tick + 1, int((time.time() - start) / camera.interval) + 1)
+        time.sleep(max(0, start + tick * camera.interval - time.time()))
+
+        if not capture_schedule.contains(datetime.datetime.now(datetime.timezone.utc)):
+            if stream.thread is not None:
+                print(f"{camera.name}: outside of scheduled hours, closing stream")
+                stream.stop()
+            continue
+        stream.start()
+
+        latest = stream.latest_frame(max_age)
+        if latest is None:
+            print(f"{camera.name}: no recent frame available")
+            failed += 1
+            continue
+        # Named for when it arrived, which with long gaps between
+        # keyframes can be well before this tick.
+        received, frame = latest
+        try:
+            output = camera.output_path(received)
+            save_frame(camera, output, frame)
+        except OSError as ex:
+            print(f"{camera.name}: couldn't write frame: {ex}")
+            failed += 1
+            continue
+        # The index may have been created since the last tick.
+        if frame_index is None:
+            frame_index = open_index(camera)
+        index_frame(camera, frame_index, output)
+        succeeded += 1
+
+    stream.stop()
+    print(f"{camera.name}: succeeded: {succeeded}, failed: {failed}")
+
+
+# Serve every camera in a config file from this one process.
+def run_config(path: str, duration: Optional[int]) -> None:
+    try:
+        cameras = config.load_config(path)
+    except config.ConfigError as ex:
+        for problem in ex.problems:
+            print(problem)
+        sys.exit(1)
+    problems = config.check_cameras(cameras)
+    if problems:
+        for problem in problems:
+            print(problem)
+        sys.exit(1)
+
+    threads = [
+        threading.Thread(target=run_daemon, args=(camera, duration), daemon=True)
+        for camera in cameras
+    ]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
 
 
 def main() -> None:
     parser = argparse.ArgumentParser(
         description="Grab timelapse frames from an RTSP source"
     )
-    parser.add_argument("--output-directory", type=str, required=True)
+    parser.add_argument(
+        "--config",
+        type=str,
+        help="capture from every camera in this TOML file (implies --daemon)",
+    )
+    parser.add_argument("--output-directory", type=str)
     parser.add_argument(
         "--output-filenames",
         type=str,
-        required=True,
-        default="cam-%Y-%m-%d_%H%M%S.png",
+        default="cam-%Y-%m-%d_%H%M%S%z.png",
+        help="frame filenames: a prefix, or a strftime template with %%Y, %%m, %%d, %%H, %%M and optionally %%S, %%f, %%z and {camera}",
     )
-    parser.add_argument("--url", type=str, required=True)
+    parser.add_argument(
+        "--name",
+        type=str,
+        help="camera name, for {camera} in --output-filenames and the frame index (default: --output-filenames)",
+    )
+    parser.add_argument("--url", type=str)
     parser.add_argument("--interval", type=int, default=10)
-    parser.add_argument("--duration", type=int, default=60)
+    parser.add_argument(
+        "--duration",
+        type=int,
+        default=None,
+        help="seconds to run for (default 60, or forever with --daemon)",
+    )
     parser.add_argument(
         "--daylight-only",
         type=bool,
@@ -64,43 +220,176 @@ def main() -> None:
     )
     parser.add_argument("--daylight-buffer-minutes", type=int, default=15)
     parser.add_argument("--city", type=str, default="Seattle")
+    parser.add_argument("--latitude", type=float, help="use these coordinates instead of --city")
+    parser.add_argument("--longitude", type=float)
+    parser.add_argument("--elevation", type=float, default=0.0, help="metres above sea level")
+    parser.add_argument("--timezone", type=str, help="IANA timezone, e.g. America/Denver")
+    parser.add_argument(
+        "--schedule",
+        action="append",
+        default=[],
+        help="only capture when this rule allows, e.g. 'mon..fri sunrise..15:30' (repeatable; replaces --daylight-only)",
+    )
+    parser.add_argument(
+        "--daemon",
+        action="store_true",
+        help="keep one RTSP session open and write the latest frame every --interval",
+    )
+    parser.add_argument(
+        "--backend",
+        choices=config.BACKENDS,
+        default="native",
+        help="capture with the built-in RTSP client or an external ffmpeg binary",
+    )
+    parser.add_argument(
+        "--max-frame-age",
+        type=float,
+        default=None,
+        help="in --daemon mode, skip a tick if the newest frame is older than this many seconds",
+    )
+    parser.add_argument(
+        "--privacy-masks",
+        action="append",
+        default=[],
+        help="region to hide, as corners in pixels 'x,y x,y x,y ...' (repeatable)",
+    )
+    parser.add_argument("--privacy-mode", choices=privacy.MODES, default="blur")
+    parser.add_argument(
+        "--mask-at-capture",
+        action="store_true",
+        help="hide --privacy-masks regions in frames before they're written, rather than when rendering",
+    )
+    parser.add_argument(
+        "--frame-index",
+        type=bool,
+        default=True,
+        action=argparse.BooleanOptionalAction,
+        help="record frames in the pile's frames.sqlite index, if it has one, as they're captured",
+    )
     args = parser.parse_args(namespace=ArgNamespace)
 
-    if args.daylight_only:
-        if not sun_is_out(args.city, args.daylight_buffer_minutes):
-            print("Skipping snapshot outside of daylight hours")
-            sys.exit(0)
+    if args.config is not None:
+        run_config(args.config, args.duration)
+        sys.exit(0)
+    if args.url is None or args.output_directory is None:
+        parser.error("--url and --output-directory are required without --config")
+    if "{camera}" in args.output_filenames and args.name is None:
+        parser.error("--output-filenames with {camera} needs --name")
 
-    output_filenames = args.output_filenames
-    if "%Y" not in output_filenames:
-        output_filenames += "-%Y-%m-%d_%H%M%S.png"
+    camera = config.CameraConfig(
+        name=args.name or args.output_filenames,
+        url=args.url,
+        output_directory=args.output_directory,
+        output_filenames=args.output_filenames,
+        interval=args.interval,
+        daylight_only=args.daylight_only,
+        daylight_buffer_minutes=args.daylight_buffer_minutes,
+        city=args.city,
+        latitude=args.latitude,
+        longitude=args.longitude,
+        elevation=args.elevation,
+        timezone=args.timezone,
+        backend=args.backend,
+        max_frame_age=args.max_frame_age,
+        schedule=args.schedule,
+        privacy_masks=args.privacy_masks,
+        privacy_mode=args.privacy_mode,
+        mask_at_capture=args.mask_at_capture,
+        frame_index=args.frame_index,
+    )
+    try:
+        capture_schedule = camera.capture_schedule()
+        camera.layout()
+        for mask in camera.privacy_masks:
+            privacy.parse_polygon(mask)
+    except (
+        frames.LayoutError,
+        schedule.ScheduleError,
+        location.LocationError,
+        privacy.PrivacyError,
+    ) as ex:
+        parser.error(str(ex))
 
-    basedir = pathlib.Path(time.strftime(args.output_directory))
-    if "%Y" not in args.output_directory:
-        basedir /= pathlib.Path(time.strftime("%Y/%m/%d/%H"))
+    if args.daemon:
+        run_daemon(camera, args.duration)
+        sys.exit(0)
+
+    if args.duration is None:
+        args.duration = 60
+
+    if not capture_schedule.contains(datetime.datetime.now(datetime.timezone.utc)):
+        print("Skipping snapshot outside of scheduled hours")
+        sys.exit(0)
+    write_metadata(camera)
+    frame_index = open_index(camera)
 
-    basedir.mkdir(mode=0o755, parents=True, exist_ok=True)
     end_time = time.time() + args.duration
 
     failed = 0
     succeeded = 0
     while time.time() < end_time:
-        output = basedir / time.strftime(output_filenames)
         begin = time.time()
+        try:
+            output = camera.output_path(begin)
+        except OSError as ex:
+            print(f"Couldn't create output directory: {ex}")
+            failed += 1
+            time.sleep(camera.interval)
+            continue
         print(f"Capturing image {succeeded + failed + 1}...")
-        res = subprocess.call(
-            f"ffmpeg -y -loglevel fatal -rtsp_transport tcp -i {args.url} -frames:v 1 {output}",
-            shell=True,
-        )
+        if camera.backend == "ffmpeg":
+            # With masks applied at capture, ffmpeg hands the frame over
+            # on stdout to be masked in memory before it's written.
+            masking = camera.mask_at_capture and bool(camera.privacy_masks)
+            command = [
+                "ffmpeg",
+                "-y",
+                "-loglevel",
+                "fatal",
+                "-rtsp_transport",
+                "tcp",
+                "-i",
+                camera.url,
+                "-frames:v",
+                "1",
+            ]
+            try:
+                if not masking:
+                    res = subprocess.call(command + [str(output)])
+                else:
+                    result = subprocess.run(
+                        command + ["-f", "image2pipe", "-c:v", "png", "-"],
+                        stdout=subprocess.PIPE,
+                    )
+                    res = result.returncode
+                    if res == 0:
+                        try:
+                            save_frame(camera, output, result.stdout)
+                        except OSError as ex:
+                            print(f"Couldn't mask frame: {ex}")
+                            res = 1
+            except OSError as ex:
+                print(f"Can't run ffmpeg: {ex}")
+                sys.exit(1)
+        else:
+            frame = capture.grab_frame(camera.url, capture.image_format(output))
+            res = 0 if frame is not None else 1
+            if frame is not None:
+                try:
+                    save_frame(camera, output, frame)
+                except OSError as ex:
+                    print(f"Couldn't write frame: {ex}")
+                    res = 1
         if res == 0:
+            index_frame(camera, frame_index, output)
             succeeded += 1
         else:
             failed += 1
         end = time.time()
-        time.sleep(max(1, int(args.interval) - (end - begin)))
+        time.sleep(max(1, int(camera.interval) - (end - begin)))
 
     print(f"Succeeded: {succeeded}, failed: {failed}")
-    if failed >= args.duration / args.interval / 2:
+    if failed >= args.duration / camera.interval / 2:
         sys.exit(1)
     else:
         sys.exit(0)
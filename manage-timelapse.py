@@ -0,0 +1,424 @@
+#!/usr/bin/python3
+
+# Housekeeping commands that don't fit the capture and filter scripts.
+
+import argparse
+import os
+import pathlib
+import sqlite3
+import sys
+from typing import List, Optional, Tuple
+
+from timelapse import cli
+from timelapse import config
+from timelapse import deflicker
+from timelapse import detection
+from timelapse import frames
+from timelapse import index
+from timelapse import location
+from timelapse import metadata
+from timelapse import overlay
+from timelapse import privacy
+from timelapse import render
+from timelapse import subtitles
+
+
+# Simple arg namespace so we get typing of our arguments.  Awkward but
+# adds type safety.
+class ArgNamespace:
+    command: str
+    config: Optional[str]
+    piles: List[str]
+    dry_run: bool
+    city: Optional[str]
+    latitude: Optional[float]
+    longitude: Optional[float]
+    elevation: float
+    timezone: Optional[str]
+    camera: Optional[str]
+    filelist: str
+    output: str
+    preset: str
+    fps: Optional[float]
+    cache_dir: Optional[str]
+    no_cache: bool
+    timestamp: Optional[str]
+    caption: Optional[str]
+    indicator: str
+    font: Optional[str]
+    font_size: Optional[int]
+    position: str
+    no_box: bool
+    no_masks: bool
+    mask: List[str]
+    mask_mode: Optional[str]
+    blur_detected: List[str]
+    detect_model: Optional[str]
+    detect_labels: Optional[str]
+    min_confidence: float
+    deflicker: bool
+    deflicker_radius: int
+    deflicker_brightness: float
+    deflicker_white_balance: float
+    subtitles: Optional[str]
+    subtitle_format: str
+    day_chapters: bool
+    annotations: Optional[str]
+    filename_layout: Optional[str]
+
+
+def check(args: ArgNamespace) -> int:
+    try:
+        cameras, problems = config.read_config(args.config)
+    except config.ConfigError as ex:
+        for problem in ex.problems:
+            print(problem)
+        return 1
+    problems += config.check_cameras(cameras)
+    if args.filename_layout is not None:
+        try:
+            frames.Layout(args.filename_layout)
+        except frames.LayoutError as ex:
+            problems.append(str(ex))
+    for problem in problems:
+        print(problem)
+    if problems:
+        return 1
+    print(f"{args.config}: {len(cameras)} camera(s), no problems found")
+    return 0
+
+
+# Rename frames whose names lack a UTC offset so that they carry one.
+# Times in the hour repeated when DST ends are resolved using each
+# file's mtime (see frames.localize).  Renamed frames are followed in
+# the pile's index, if it has one.
+def migrate_names(args: ArgNamespace) -> int:
+    renamed = 0
+    skipped = 0
+    indexes = index.Indexes()
+    for pile in args.piles:
+        try:
+            timezone = cli.find_location(args, pile).tzinfo  # type: ignore
+            layout = cli.find_layout(args, pile)  # type: ignore
+        except (frames.LayoutError, location.LocationError) as ex:
+            print(f"{pile}: {ex}")
+            return 1
+        for root, dirs, files in os.walk(pile):
+            for filename in files:
+                path = os.path.join(root, filename)
+                parsed = frames.read_name(filename, timezone, path, layout)
+                new_name = None
+                if parsed is not None:
+                    new_name = frames.migrated_name(filename, parsed[0], layout)
+                if new_name is None:
+                    continue
+                new_path = os.path.join(root, new_name)
+                if os.path.exists(new_path):
+                    print(f"Not renaming {path}: {new_name} already exists")
+                    skipped += 1
+                    continue
+                if args.dry_run:
+                    print(f"{path} -> {new_name}")
+                else:
+                    os.rename(path, new_path)
+                    try:
+                        found = indexes.for_frame(new_path)
+                        if found is not None:
+                            found.rename(path, new_path)
+                    except (index.FrameIndexError, sqlite3.Error) as ex:
+                        print(f"Couldn't update the index for {path}: {ex}")
+                        skipped += 1
+                renamed += 1
+    verb = "Would rename" if args.dry_run else "Renamed"
+    print(f"{verb} {renamed} frames, skipped {skipped}")
+    return 1 if skipped else 0
+
+
+# The camera's privacy masks that still need applying, and how, from
+# --config or the metadata next to the frames.  Masks already applied
+# at capture time aren't applied again.
+def camera_masks(args: ArgNamespace, basedir: str) -> Tuple[List[str], str]:
+    if args.config is not None:
+        camera = cli.config_camera(args)  # type: ignore
+        if camera.mask_at_capture:
+            return [], camera.privacy_mode
+        return camera.privacy_masks, camera.privacy_mode
+    found = metadata.find(pathlib.Path(basedir), args.camera)
+    settings = found[1].get("privacy", {}) if found is not None else {}
+    if settings.get("masked_at_capture"):
+        return [], settings.get("mode", "blur")
+    return settings.get("masks", []), settings.get("mode", "blur")
+
+
+# Create or update the frame index of each pile (see
+# timelapse/index.py).
+def reindex(args: ArgNamespace) -> int:
+    for pile in args.piles:
+        try:
+            timezone = cli.find_location(args, pile).tzinfo  # type: ignore
+            camera = cli.find_camera_name(args, pile)  # type: ignore
+            layout = cli.find_layout(args, pile)  # type: ignore
+            frame_index = index.FrameIndex(pathlib.Path(pile), create=True)
+        except (
+            config.ConfigError,
+            frames.LayoutError,
+            index.FrameIndexError,
+            location.LocationError,
+        ) as ex:
+            print(f"{pile}: {ex}")
+            return 1
+        changed, removed = frame_index.reindex(timezone, camera, layout)
+        frame_index.close()
+        print(f"{pile}: indexed {changed} new or changed frames, removed {removed}")
+    return 0
+
+
+# Processing to do on each frame as it's rendered, per the options.
+def render_steps(
+    args: ArgNamespace, where: location.Location, basedir: str
+) -> List[render.Step]:
+    steps: List[render.Step] = []
+    masks, mode = ([], "blur") if args.no_masks else camera_masks(args, basedir)
+    masks = masks + args.mask
+    if masks or args.blur_detected:
+        detections = None
+        labels = [
+            detection.parse_condition(text, args.min_confidence) for text in args.blur_detected
+        ]
+        if labels:
+            if args.detect_model is None:
+                raise render.RenderError("--blur-detected needs --detect-model")
+            detector = detection.Detector(args.detect_model, args.detect_labels)
+            detection.check_conditions(detector, labels)
+            detections = detection.DetectionCache(detector, index.Indexes())
+        try:
+            polygons = [privacy.parse_polygon(mask) for mask in masks]
+        except privacy.PrivacyError as ex:
+            raise render.RenderError(str(ex))
+        steps.append(privacy.Privacy(polygons, args.mask_mode or mode, detections, labels))
+    if args.deflicker:
+        steps.append(
+            deflicker.Deflicker(
+                radius=args.deflicker_radius,
+                brightness=args.deflicker_brightness,
+                white_balance=args.deflicker_white_balance,
+            )
+        )
+    caption = args.caption
+    if caption is None and args.config is not None:
+        caption = cli.config_camera(args).caption  # type: ignore
+    if args.timestamp or caption or args.indicator != "none":
+        steps.append(
+            overlay.Overlay(
+                where,
+                camera=cli.find_camera_name(args, basedir) or "",  # type: ignore
+                timestamp=args.timestamp,
+                caption=caption,
+                indicator=args.indicator,
+                font=args.font,
+                font_size=args.font_size,
+                position=args.position,
+                box=not args.no_box,
+            )
+        )
+    return steps
+
+
+# Encode the frames listed in a file (one path per line, as written by
+# filter-timelapse-frames.py; "-" reads standard input) into a video.
+# Unless told not to, days are encoded separately and cached next to
+# the output, so re-rendering a growing project only encodes new days.
+def render_video(args: ArgNamespace) -> int:
+    if args.filelist == "-":
+        lines = sys.stdin.read().splitlines()
+    else:
+        with open(args.filelist) as f:
+            lines = f.read().splitlines()
+    paths = [line for line in lines if line.strip()]
+    output = pathlib.Path(args.output)
+    preset = render.PRESETS[args.preset]
+    if not paths:
+        print(f"{args.output}: no frames to render")
+        return 1
+    try:
+        basedir = os.path.dirname(paths[0])
+        where = cli.find_location(args, basedir)  # type: ignore
+        layout = cli.find_layout(args, basedir)  # type: ignore
+        found = []
+        for path in paths:
+            parsed = frames.read_name(os.path.basename(path), where.tzinfo, path, layout)
+            if parsed is None:
+                print(f"{path}: no capture time in the filename")
+                return 1
+            found.append(frames.Frame(parsed[0], path, parsed[1]))
+
+        steps = render_steps(args, where, basedir)
+
+        chapters = []
+        fps = args.fps or preset.fps
+        if args.day_chapters:
+            chapters += subtitles.day_chapters(found, fps, where.tzinfo)
+        if args.annotations:
+            events = subtitles.read_annotations(args.annotations, where.tzinfo)
+            chapters += subtitles.annotation_chapters(found, fps, events)
+
+        if args.no_cache:
+            render.render(found, output, preset, fps, steps)
+        else:
+            cache_dir = args.cache_dir or output.parent / f".{output.name}.segments"
+            render.render_segments(
+                found, output, preset, pathlib.Path(cache_dir), where.tzinfo, fps, steps
+            )
+        if chapters:
+            subtitles.add_chapters(output, chapters, len(found) / fps, preset)
+        if args.subtitles:
+            subtitles.write_subtitles(
+                pathlib.Path(args.subtitles), found, fps, where.tzinfo, args.subtitle_format
+            )
+    except (
+        config.ConfigError,
+        detection.DetectionError,
+        frames.LayoutError,
+        location.LocationError,
+        render.RenderError,
+    ) as ex:
+        print(f"{args.output}: {ex}")
+        return 1
+    return 0
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(description="Timelapse housekeeping")
+    subparsers = parser.add_subparsers(dest="command", required=True)
+    check_parser = subparsers.add_parser(
+        "check", help="validate a camera config file without capturing anything"
+    )
+    check_parser.add_argument("config")
+    cli.add_layout_argument(check_parser)
+    check_parser.set_defaults(func=check)
+    migrate_parser = subparsers.add_parser(
+        "migrate-names",
+        help="add UTC offsets to frame names that don't have them",
+    )
+    migrate_parser.add_argument("piles", nargs="+")
+    migrate_parser.add_argument("--dry-run", action="store_true")
+    cli.add_location_arguments(migrate_parser)
+    cli.add_layout_argument(migrate_parser)
+    migrate_parser.set_defaults(func=migrate_names)
+    reindex_parser = subparsers.add_parser(
+        "reindex", help="create or update the frame index of piles of frames"
+    )
+    reindex_parser.add_argument("piles", nargs="+", help="top directory of each pile")
+    cli.add_location_arguments(reindex_parser)
+    cli.add_layout_argument(reindex_parser)
+    reindex_parser.set_defaults(func=reindex)
+    render_parser = subparsers.add_parser(
+        "render", help="encode a list of frames into a video"
+    )
+    render_parser.add_argument("filelist", help="frames to encode, one per line, or - for stdin")
+    render_parser.add_argument("output")
+    render_parser.add_argument(
+        "--preset",
+        choices=sorted(render.PRESETS),
+        default="archive-quality",
+        help="; ".join(f"{name}: {p.description}" for name, p in render.PRESETS.items()),
+    )
+    render_parser.add_argument("--fps", type=float, help="frames per second of video")
+    render_parser.add_argument(
+        "--cache-dir",
+        help="where to keep per-day segments (default: .OUTPUT.segments next to OUTPUT)",
+    )
+    render_parser.add_argument(
+        "--no-cache", action="store_true", help="encode everything in one go, caching nothing"
+    )
+    render_parser.add_argument(
+        "--no-masks",
+        action="store_true",
+        help="don't hide the camera's privacy masks",
+    )
+    render_parser.add_argument(
+        "--mask",
+        action="append",
+        default=[],
+        help="another region to hide, as corners in pixels 'x,y x,y x,y ...' (repeatable)",
+    )
+    render_parser.add_argument(
+        "--mask-mode", choices=privacy.MODES, help="default: the camera's privacy_mode"
+    )
+    render_parser.add_argument(
+        "--blur-detected",
+        action="append",
+        default=[],
+        help="also hide what --detect-model finds, as LABEL or LABEL:CONFIDENCE, e.g. person (repeatable)",
+    )
+    render_parser.add_argument("--detect-model", help="ONNX object detection model")
+    render_parser.add_argument(
+        "--detect-labels", help="class names for --detect-model, one per line (default: COCO)"
+    )
+    render_parser.add_argument(
+        "--min-confidence",
+        type=float,
+        default=0.3,
+        help="for --blur-detected, the confidence when none is given (default: 0.3)",
+    )
+    render_parser.add_argument(
+        "--deflicker", action="store_true", help="even out brightness and colour between frames"
+    )
+    render_parser.add_argument(
+        "--deflicker-radius",
+        type=int,
+        default=7,
+        help="frames either side to smooth over (default: 7)",
+    )
+    render_parser.add_argument(
+        "--deflicker-brightness",
+        type=float,
+        default=1.0,
+        help="how strongly to even out brightness, 0 to 1 (default: 1)",
+    )
+    render_parser.add_argument(
+        "--deflicker-white-balance",
+        type=float,
+        default=0.5,
+        help="how strongly to even out colour, 0 to 1 (default: 0.5)",
+    )
+    render_parser.add_argument(
+        "--timestamp",
+        nargs="?",
+        const=overlay.DEFAULT_TIMESTAMP,
+        help="draw each frame's capture time, optionally in this strftime format",
+    )
+    render_parser.add_argument(
+        "--caption",
+        help="caption template: strftime fields and {camera} (default: the camera's caption in --config)",
+    )
+    render_parser.add_argument("--indicator", choices=overlay.INDICATORS, default="none")
+    render_parser.add_argument("--font", help="TrueType font file for overlays")
+    render_parser.add_argument("--font-size", type=int, help="overlay text height in pixels")
+    render_parser.add_argument("--position", choices=overlay.POSITIONS, default="bottom-left")
+    render_parser.add_argument("--no-box", action="store_true", help="no background behind overlays")
+    render_parser.add_argument(
+        "--subtitles",
+        metavar="FILE",
+        help="write a .vtt or .srt file giving the capture time through the video",
+    )
+    render_parser.add_argument(
+        "--subtitle-format",
+        default=subtitles.DEFAULT_FORMAT,
+        help="strftime format for subtitle times",
+    )
+    render_parser.add_argument(
+        "--day-chapters", action="store_true", help="add a chapter at the start of each day"
+    )
+    render_parser.add_argument(
+        "--annotations", metavar="FILE", help="add chapters for the events listed in FILE"
+    )
+    cli.add_location_arguments(render_parser)
+    cli.add_layout_argument(render_parser)
+    render_parser.set_defaults(func=render_video)
+    args = parser.parse_args(namespace=ArgNamespace)
+    sys.exit(args.func(args))  # type: ignore
+
+
+if __name__ == "__main__":
+    main()
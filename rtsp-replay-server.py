@@ -0,0 +1,157 @@
+#!/usr/bin/python3
+
+# A stand-in for a camera: record a few seconds of a real RTSP stream,
+# then serve that recording back over RTSP (TCP-interleaved only) so
+# capture can be tested without the camera.
+
+import argparse
+import socketserver
+import sys
+import threading
+import time
+from typing import Dict, List, Tuple
+
+from timelapse import rtsp
+
+
+# Simple arg namespace so we get typing of our arguments.  Awkward but
+# adds type safety.
+class ArgNamespace:
+    command: str
+    url: str
+    recording: str
+    seconds: float
+    host: str
+    port: int
+    loop: bool
+
+
+class ReplayHandler(socketserver.StreamRequestHandler):
+    sdp: str
+    packets: List[Tuple[float, bytes]]
+    loop: bool
+
+    def setup(self) -> None:
+        super().setup()
+        self.write_lock = threading.Lock()
+        self.playing = threading.Event()
+        self.closed = threading.Event()
+        self.channel = 0
+
+    def respond(self, cseq: str, headers: Dict[str, str], body: bytes = b"") -> None:
+        lines = ["RTSP/1.0 200 OK", f"CSeq: {cseq}"]
+        lines += [f"{key}: {value}" for key, value in headers.items()]
+        if body:
+            lines.append(f"Content-Length: {len(body)}")
+        with self.write_lock:
+            self.wfile.write(("\r\n".join(lines) + "\r\n\r\n").encode("utf-8") + body)
+
+    def handle(self) -> None:
+        try:
+            while self.serve_request():
+                pass
+        except OSError:
+            pass
+        self.closed.set()
+
+    def serve_request(self) -> bool:
+        request_line = self.rfile.readline().decode("utf-8", errors="replace").strip()
+        if not request_line:
+            return False
+        method, url = request_line.split(" ")[:2]
+        headers: Dict[str, str] = {}
+        while True:
+            line = self.rfile.readline().decode("utf-8", errors="replace").strip()
+            if not line:
+                break
+            key, _, value = line.partition(":")
+            headers[key.strip().lower()] = value.strip()
+        self.rfile.read(int(headers.get("content-length", "0")))
+        cseq = headers.get("cseq", "0")
+        session = {"Session": "12345678;timeout=60"}
+
+        if method == "OPTIONS":
+            self.respond(cseq, {"Public": "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN"})
+        elif method == "DESCRIBE":
+            self.respond(
+                cseq,
+                {"Content-Type": "application/sdp", "Content-Base": url + "/"},
+                self.sdp.encode("utf-8"),
+            )
+        elif method == "SETUP":
+            transport = headers.get("transport", "")
+            for option in transport.split(";"):
+                if option.startswith("interleaved="):
+                    self.channel = int(option[len("interleaved=") :].split("-")[0])
+            transport = f"RTP/AVP/TCP;unicast;interleaved={self.channel}-{self.channel + 1}"
+            self.respond(cseq, {**session, "Transport": transport})
+        elif method == "PLAY":
+            self.respond(cseq, session)
+            if not self.playing.is_set():
+                self.playing.set()
+                threading.Thread(target=self.play, daemon=True).start()
+        elif method == "TEARDOWN":
+            self.respond(cseq, session)
+            return False
+        else:
+            self.respond(cseq, session)
+        return True
+
+    # Send the recorded packets with their original pacing.
+    def play(self) -> None:
+        try:
+            while not self.closed.is_set():
+                start = time.monotonic()
+                for offset, packet in self.packets:
+                    delay = start + offset - time.monotonic()
+                    if self.closed.wait(max(0, delay)):
+                        return
+                    header = bytes([0x24, self.channel]) + len(packet).to_bytes(2, "big")
+                    with self.write_lock:
+                        self.wfile.write(header + packet)
+                if not self.loop:
+                    return
+        except OSError:
+            pass
+
+
+class ReplayServer(socketserver.ThreadingTCPServer):
+    allow_reuse_address = True
+    daemon_threads = True
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(description="Record and replay RTSP streams")
+    subparsers = parser.add_subparsers(dest="command", required=True)
+    record_parser = subparsers.add_parser("record", help="record a camera's stream")
+    record_parser.add_argument("url")
+    record_parser.add_argument("recording")
+    record_parser.add_argument("--seconds", type=float, default=30)
+    serve_parser = subparsers.add_parser("serve", help="serve a recording over RTSP")
+    serve_parser.add_argument("recording")
+    serve_parser.add_argument("--host", type=str, default="127.0.0.1")
+    serve_parser.add_argument("--port", type=int, default=8554)
+    serve_parser.add_argument(
+        "--loop",
+        type=bool,
+        default=True,
+        action=argparse.BooleanOptionalAction,
+    )
+    args = parser.parse_args(namespace=ArgNamespace)
+
+    if args.command == "record":
+        with open(args.recording, "wb") as f:
+            count = rtsp.record(rtsp.RtspClient(args.url), f, args.seconds)
+        print(f"Recorded {count} packets to {args.recording}")
+        sys.exit(0)
+
+    with open(args.recording, "rb") as f:
+        ReplayHandler.sdp, ReplayHandler.packets = rtsp.read_recording(f)
+    ReplayHandler.loop = args.loop
+    with ReplayServer((args.host, args.port), ReplayHandler) as server:
+        print(f"Serving {args.recording} at rtsp://{args.host}:{args.port}/replay")
+        server.serve_forever()
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,3 @@
+# Shared code for the timelapse scripts in the top level of this
+# repository.  The scripts put their own directory on sys.path, so
+# `import timelapse.foo` works when they're run from anywhere.
@@ -0,0 +1,78 @@
+# How much is going on between one frame and the next: the average
+# change between small grayscale thumbnails of consecutive frames.  Each
+# thumbnail has its average brightness taken out first, so a cloud
+# passing over the whole scene scores far lower than a crane swinging
+# across it.
+
+import os
+from typing import List, Optional, Tuple
+
+from timelapse import frames
+from timelapse import index
+
+THUMBNAIL_WIDTH = 160
+# Per-pixel differences up to this much are sensor noise and
+# compression, not activity.
+NOISE_FLOOR = 4
+
+Thumbnail = Tuple[List[float], Tuple[int, int]]
+
+
+def thumbnail(path: str) -> Thumbnail:
+    import PIL.Image  # type: ignore
+
+    with PIL.Image.open(path) as image:
+        height = max(1, image.height * THUMBNAIL_WIDTH // image.width)
+        small = image.convert("L").resize((THUMBNAIL_WIDTH, height))
+    data = small.tobytes()
+    mean = sum(data) / len(data)
+    return [value - mean for value in data], small.size
+
+
+def difference(a: Thumbnail, b: Thumbnail) -> float:
+    if a[1] != b[1]:
+        return 0.0
+    return sum(max(0.0, abs(x - y) - NOISE_FLOOR) for x, y in zip(a[0], b[0])) / len(a[0])
+
+
+# Activity score for each frame: how much changed since the frame
+# before it (0 for the first frame, and for frames that can't be read
+# or follow one that can't).  Scores are kept in frame indexes, as
+# "activity after" the frame they were compared with, so they're only
+# worked out again when the frame before changes.
+def scores(found: List[frames.Frame], indexes: Optional[index.Indexes] = None) -> List[float]:
+    result = []
+    previous: Optional[frames.Frame] = None
+    # The previous frame's thumbnail, if it's been made.
+    previous_thumbnail: Optional[Thumbnail] = None
+    previous_read = False
+    for frame in found:
+        current: Optional[Thumbnail] = None
+        current_read = False
+        if previous is None:
+            score = 0.0
+        else:
+            name = f"activity after {os.path.basename(previous.path)}"
+            cached = indexes.scores(frame.path).get(name) if indexes is not None else None
+            if isinstance(cached, (int, float)):
+                score = float(cached)
+            else:
+                if not previous_read:
+                    previous_thumbnail = read_thumbnail(previous.path)
+                current, current_read = read_thumbnail(frame.path), True
+                if previous_thumbnail is None or current is None:
+                    score = 0.0
+                else:
+                    score = difference(previous_thumbnail, current)
+                    if indexes is not None:
+                        indexes.set_scores(frame.path, {name: score})
+        result.append(score)
+        previous, previous_thumbnail, previous_read = frame, current, current_read
+    return result
+
+
+def read_thumbnail(path: str) -> Optional[Thumbnail]:
+    try:
+        return thumbnail(path)
+    except OSError:
+        return None
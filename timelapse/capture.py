@@ -0,0 +1,277 @@
+# Frame sources for grab-timelapse-frame.py.  The native backend uses
+# our own RTSP client and only decodes the keyframes it actually
+# writes out; the ffmpeg backend is kept for cameras the native client
+# can't handle.
+
+import io
+import os
+import pathlib
+import subprocess
+import threading
+import time
+from typing import Optional, Tuple
+
+from timelapse import rtsp
+
+
+PNG_SIGNATURE = b"\x89PNG\r\n\x1a\n"
+
+
+# Read exactly one PNG image from a stream of concatenated PNGs (what
+# ffmpeg's image2pipe muxer produces).  Returns None at end of stream.
+def read_png(stream) -> Optional[bytes]:
+    signature = stream.read(len(PNG_SIGNATURE))
+    if len(signature) < len(PNG_SIGNATURE):
+        return None
+    if signature != PNG_SIGNATURE:
+        raise ValueError("stream is not a sequence of PNG images")
+    chunks = [signature]
+    while True:
+        header = stream.read(8)
+        if len(header) < 8:
+            return None
+        length = int.from_bytes(header[:4], "big")
+        body = stream.read(length + 4)  # chunk data plus CRC
+        if len(body) < length + 4:
+            return None
+        chunks += [header, body]
+        if header[4:] == b"IEND":
+            return b"".join(chunks)
+
+
+# Pillow format name for an output filename, e.g. "PNG" for foo.png.
+def image_format(output: pathlib.Path) -> str:
+    import PIL.Image  # type: ignore
+
+    return PIL.Image.registered_extensions().get(output.suffix.lower(), "PNG")
+
+
+# Decode a single keyframe access unit into encoded image bytes.  A
+# fresh decoder is used each time: keyframes are self-contained, and
+# flushing guarantees the frame comes out even from decoders that
+# would otherwise buffer it.  `name` is the camera's, for messages.
+def decode_keyframe(
+    codec: str, access_unit: rtsp.AccessUnit, fmt: str, name: Optional[str] = None
+) -> Optional[bytes]:
+    import av  # type: ignore
+
+    context = av.CodecContext.create(codec, "r")
+    try:
+        frames = list(context.decode(av.Packet(access_unit.annexb())))
+        frames += context.decode(None)
+    except av.AVError as ex:
+        if name:
+            print(f"{name}: failed to decode keyframe: {ex}")
+        else:
+            print(f"Failed to decode keyframe: {ex}")
+        return None
+    if not frames:
+        return None
+    output = io.BytesIO()
+    frames[-1].to_image().save(output, format=fmt)
+    return output.getvalue()
+
+
+# Write a frame via a temporary file so readers never see a partial
+# image.
+def write_frame(output: pathlib.Path, frame: bytes) -> None:
+    tmp = output.with_name(f".{output.name}.tmp")
+    with open(tmp, "wb") as f:
+        f.write(frame)
+    os.replace(tmp, output)
+
+
+# Connect, wait for the next complete keyframe, decode it and hang up.
+def grab_frame(url: str, fmt: str, timeout: float = 30.0) -> Optional[bytes]:
+    client = rtsp.RtspClient(url, timeout=timeout)
+    deadline = time.monotonic() + timeout
+    try:
+        track = client.open()
+        for access_unit in client.access_units():
+            if access_unit.keyframe:
+                return decode_keyframe(track.codec, access_unit, fmt)
+            if time.monotonic() > deadline:
+                print("Timed out waiting for a keyframe")
+                return None
+    except (OSError, rtsp.RtspError) as ex:
+        print(f"Capture from {client.url} failed: {ex}")
+    finally:
+        client.close()
+    return None
+
+
+# Base class for sources that keep a single RTSP session open in a
+# background thread and remember the most recent frame.  If the
+# session drops it is reopened with a growing backoff.  Sessions are
+# started and interrupted holding the lock, so stop() can't miss one
+# that's just starting.  Messages start with the camera's name.
+class FrameStream:
+    def __init__(self, url: str, name: str) -> None:
+        self.url = url
+        self.name = name
+        self.lock = threading.Lock()
+        self.latest: Optional[Tuple[float, object]] = None
+        self.stopping = threading.Event()
+        self.thread: Optional[threading.Thread] = None
+
+    def start(self) -> None:
+        if self.thread is not None and self.thread.is_alive():
+            return
+        self.stopping.clear()
+        self.thread = threading.Thread(target=self.run, daemon=True)
+        self.thread.start()
+
+    def stop(self) -> None:
+        self.stopping.set()
+        self.interrupt()
+        if self.thread is not None:
+            self.thread.join()
+        self.thread = None
+        with self.lock:
+            self.latest = None
+
+    def remember(self, frame: object) -> None:
+        with self.lock:
+            self.latest = (time.time(), frame)
+
+    # Most recent frame and when it arrived, or None if there isn't one
+    # at most max_age seconds old.
+    def latest_frame(self, max_age: float) -> Optional[Tuple[float, bytes]]:
+        with self.lock:
+            if self.latest is None:
+                return None
+            received, frame = self.latest
+        if time.time() - received > max_age:
+            return None
+        encoded = self.encode(frame)
+        return None if encoded is None else (received, encoded)
+
+    def run(self) -> None:
+        backoff = 1
+        while not self.stopping.is_set():
+            if self.stream():
+                backoff = 1
+            if self.stopping.is_set():
+                break
+            print(f"{self.name}: stream ended, reconnecting in {backoff}s")
+            self.stopping.wait(backoff)
+            backoff = min(backoff * 2, 60)
+
+    # Run one session until it ends; returns True if any frames arrived.
+    def stream(self) -> bool:
+        raise NotImplementedError
+
+    # Stop a running session from another thread.
+    def interrupt(self) -> None:
+        raise NotImplementedError
+
+    def encode(self, frame: object) -> Optional[bytes]:
+        raise NotImplementedError
+
+
+# Keeps one ffmpeg process decoding the stream to PNGs at frame_rate.
+class FfmpegFrameStream(FrameStream):
+    def __init__(self, url: str, name: str, frame_rate: float) -> None:
+        super().__init__(url, name)
+        self.frame_rate = frame_rate
+        self.process: Optional[subprocess.Popen] = None
+
+    def stream(self) -> bool:
+        with self.lock:
+            try:
+                self.process = subprocess.Popen(
+                    [
+                        "ffmpeg",
+                        "-loglevel",
+                        "fatal",
+                        "-rtsp_transport",
+                        "tcp",
+                        "-i",
+                        self.url,
+                        "-vf",
+                        f"fps={self.frame_rate}",
+                        "-f",
+                        "image2pipe",
+                        "-c:v",
+                        "png",
+                        "-",
+                    ],
+                    stdin=subprocess.DEVNULL,
+                    stdout=subprocess.PIPE,
+                )
+            except OSError as ex:
+                print(f"{self.name}: can't run ffmpeg: {ex}")
+                return False
+            process = self.process
+            if self.stopping.is_set():
+                process.kill()
+        assert process.stdout is not None
+        received = False
+        try:
+            while True:
+                frame = read_png(process.stdout)
+                if frame is None:
+                    break
+                self.remember(frame)
+                received = True
+        except ValueError as ex:
+            print(f"{self.name}: bad data from ffmpeg: {ex}")
+        process.kill()
+        process.wait()
+        return received
+
+    def interrupt(self) -> None:
+        with self.lock:
+            if self.process is not None:
+                self.process.kill()
+
+    def encode(self, frame: object) -> Optional[bytes]:
+        assert isinstance(frame, bytes)
+        return frame
+
+
+# Keeps one native RTSP session open, remembering the latest complete
+# keyframe without decoding it; decoding happens only when a frame is
+# actually asked for.
+class NativeFrameStream(FrameStream):
+    def __init__(self, url: str, name: str, fmt: str) -> None:
+        super().__init__(url, name)
+        self.fmt = fmt
+        self.client: Optional[rtsp.RtspClient] = None
+        self.codec = ""
+
+    def stream(self) -> bool:
+        with self.lock:
+            if self.stopping.is_set():
+                return False
+            try:
+                self.client = client = rtsp.RtspClient(self.url)
+            except rtsp.RtspError as ex:
+                print(f"{self.name}: can't stream from {self.url}: {ex}")
+                return False
+        received = False
+        try:
+            self.codec = client.open().codec
+            for access_unit in client.access_units():
+                # Closing the client before it had connected doesn't
+                # stop it, so check as well.
+                if self.stopping.is_set():
+                    break
+                if access_unit.keyframe:
+                    self.remember(access_unit)
+                    received = True
+        except (OSError, rtsp.RtspError) as ex:
+            if not self.stopping.is_set():
+                print(f"{self.name}: stream from {client.url} failed: {ex}")
+        finally:
+            client.close()
+        return received
+
+    def interrupt(self) -> None:
+        with self.lock:
+            if self.client is not None:
+                self.client.close()
+
+    def encode(self, frame: object) -> Optional[bytes]:
+        assert isinstance(frame, rtsp.AccessUnit)
+        return decode_keyframe(self.codec, frame, self.fmt, self.name)
@@ -0,0 +1,104 @@
+# Command line options shared by the scripts that work on piles of
+# frames after they've been captured.
+
+import argparse
+import pathlib
+import sys
+from typing import Optional
+
+from timelapse import config
+from timelapse import frames
+from timelapse import location
+from timelapse import metadata
+
+
+def add_location_arguments(parser: argparse.ArgumentParser) -> None:
+    parser.add_argument("--city", type=str)
+    parser.add_argument("--latitude", type=float)
+    parser.add_argument("--longitude", type=float)
+    parser.add_argument("--elevation", type=float, default=0.0, help="metres above sea level")
+    parser.add_argument("--timezone", type=str, help="IANA timezone, e.g. America/Denver")
+    parser.add_argument("--config", type=str, help="take the location from this camera config")
+    parser.add_argument("--camera", type=str, help="camera name in --config or the pile's metadata")
+
+
+def add_layout_argument(parser: argparse.ArgumentParser) -> None:
+    parser.add_argument(
+        "--filename-layout",
+        type=str,
+        help="strftime template frames are named with, e.g. '{camera}-%%Y%%m%%d_%%H%%M%%S.%%f.jpg' (default: from --config or the pile's metadata)",
+    )
+
+
+# The camera picked with --config and --camera; --camera can be left
+# out if the config only has one.
+def config_camera(args: argparse.Namespace) -> config.CameraConfig:
+    cameras = config.load_config(args.config)
+    if args.camera is None and len(cameras) == 1:
+        return cameras[0]
+    if args.camera is None:
+        raise config.ConfigError(["--camera is needed with --config"])
+    return config.find_camera(cameras, args.camera)
+
+
+# The camera's name, from --config or the metadata capture left next
+# to the frames, or None if neither says.
+def find_camera_name(args: argparse.Namespace, basedir: str) -> Optional[str]:
+    if args.config is not None:
+        return config_camera(args).name
+    found = metadata.find(pathlib.Path(basedir), args.camera)
+    if found is not None:
+        return found[1].get("camera")
+    return None
+
+
+# Work out where the frames under basedir were taken: explicit
+# coordinates or city first, then a camera from a config file, then
+# the metadata capture left next to the frames.  Seattle is the last
+# resort, as it always used to be.
+def find_location(args: argparse.Namespace, basedir: str) -> location.Location:
+    if args.latitude is not None or args.longitude is not None or args.timezone:
+        if args.latitude is None or args.longitude is None or not args.timezone:
+            raise location.LocationError(
+                "--latitude, --longitude and --timezone must be given together"
+            )
+        return location.Location(
+            name=basedir,
+            latitude=args.latitude,
+            longitude=args.longitude,
+            elevation=args.elevation,
+            timezone=args.timezone,
+        )
+    if args.city is not None:
+        return location.lookup_city(args.city)
+    if args.config is not None:
+        try:
+            return config_camera(args).location()
+        except config.ConfigError as ex:
+            raise location.LocationError(str(ex))
+    found = metadata.find(pathlib.Path(basedir), args.camera)
+    if found is not None:
+        return metadata.location_from(found[1])
+    print(
+        f"No {metadata.FILENAME} found for {basedir}, assuming Seattle",
+        file=sys.stderr,
+    )
+    return location.lookup_city("Seattle")
+
+
+# How frames under basedir are named: --filename-layout, then the
+# camera's output_filenames from a config file, then what capture
+# recorded in the metadata next to the frames.  None if none of them
+# say, so names are read the way they always have been.
+def find_layout(args: argparse.Namespace, basedir: str) -> Optional[frames.Layout]:
+    if args.filename_layout is not None:
+        return frames.Layout(args.filename_layout)
+    if args.config is not None:
+        try:
+            return config_camera(args).layout()
+        except config.ConfigError as ex:
+            raise location.LocationError(str(ex))
+    found = metadata.find(pathlib.Path(basedir), args.camera)
+    if found is not None and "filenames" in found[1]:
+        return frames.Layout(found[1]["filenames"])
+    return None
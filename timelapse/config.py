@@ -0,0 +1,312 @@
+# Multi-camera TOML configuration.  A config file has an optional
+# [defaults] table and one [[camera]] table per camera; any camera key
+# can also go in [defaults].  For example:
+#
+#   [defaults]
+#   city = "Seattle"
+#   interval = 10
+#
+#   [[camera]]
+#   name = "backyard"
+#   url = "rtsp://192.168.1.1:7447/abcdef"
+#   output_directory = "/nas/timelapse/backyard"
+#
+# load_config() catches structural problems (missing or misspelled
+# keys, wrong types); check_cameras() catches problems that need a
+# closer look, like bad URLs or two cameras writing the same files.
+# read_config() reports the first kind without stopping, so both can be
+# listed at once.
+# Cameras can also carry a schedule (see timelapse/schedule.py):
+#
+#   schedule = ["mon..fri sunrise-15m..15:30", "exclude ics:holidays.ics"]
+#
+# and privacy masks (see timelapse/privacy.py):
+#
+#   privacy_masks = ["0,600 500,600 500,1080 0,1080"]
+#
+# Frame filenames follow output_filenames, a layout as described in
+# timelapse/frames.py, which can name the camera:
+#
+#   output_filenames = "{camera}-%Y-%m-%d_%H%M%S.%f%z.jpg"
+
+import dataclasses
+import datetime
+import os
+import pathlib
+import tomllib
+import urllib.parse
+from typing import Any, Dict, List, Optional, Tuple
+
+import timelapse.frames
+import timelapse.location
+import timelapse.metadata
+import timelapse.privacy
+import timelapse.schedule
+
+
+class ConfigError(Exception):
+    def __init__(self, problems: List[str]) -> None:
+        super().__init__("\n".join(problems))
+        self.problems = problems
+
+
+@dataclasses.dataclass
+class CameraConfig:
+    name: str
+    url: str
+    output_directory: str
+    output_filenames: str = "cam-%Y-%m-%d_%H%M%S%z.png"
+    interval: int = 10
+    daylight_only: bool = True
+    daylight_buffer_minutes: int = 15
+    city: str = "Seattle"
+    # Explicit coordinates take precedence over city; timezone is an
+    # IANA name like "America/Denver" and elevation is in metres.
+    latitude: Optional[float] = None
+    longitude: Optional[float] = None
+    elevation: float = 0.0
+    timezone: Optional[str] = None
+    backend: str = "native"
+    max_frame_age: Optional[float] = None
+    # Rules as described in timelapse/schedule.py; when empty,
+    # daylight_only and daylight_buffer_minutes apply.
+    schedule: List[str] = dataclasses.field(default_factory=list)
+    # Caption drawn on rendered videos; see timelapse/overlay.py.
+    caption: Optional[str] = None
+    # Regions to hide, as described in timelapse/privacy.py: blurred or
+    # blacked out when rendering, or in every frame as it's captured
+    # with mask_at_capture.
+    privacy_masks: List[str] = dataclasses.field(default_factory=list)
+    privacy_mode: str = "blur"
+    mask_at_capture: bool = False
+    # Keep the pile's frame index (timelapse/index.py), if it has one, up
+    # to date as frames are captured.
+    frame_index: bool = True
+
+    # strftime template for frame filenames; a plain prefix like "cam1"
+    # gets a timestamp (with UTC offset, see timelapse/frames.py) and
+    # extension appended.
+    def filename_template(self) -> str:
+        if "%Y" not in self.output_filenames:
+            return self.output_filenames + "-%Y-%m-%d_%H%M%S%z.png"
+        return self.output_filenames
+
+    # The filename template as a layout, for writing names and reading
+    # them back.
+    def layout(self) -> timelapse.frames.Layout:
+        return timelapse.frames.Layout(self.filename_template())
+
+    # strftime template for the directory frames go in; without any
+    # date fields of its own, frames are split into hourly directories.
+    def directory_template(self) -> str:
+        if "%Y" not in self.output_directory:
+            return os.path.join(self.output_directory, "%Y/%m/%d/%H")
+        return self.output_directory
+
+    def capture_schedule(self) -> timelapse.schedule.Schedule:
+        rules = self.schedule
+        if not rules and self.daylight_only:
+            buffer = self.daylight_buffer_minutes
+            rules = [f"dawn-{buffer}m..dusk+{buffer}m"]
+        return timelapse.schedule.Schedule.parse(rules, self.location())
+
+    def location(self) -> timelapse.location.Location:
+        if self.latitude is None and self.longitude is None and self.timezone is None:
+            return timelapse.location.lookup_city(self.city)
+        if self.latitude is None or self.longitude is None or self.timezone is None:
+            raise timelapse.location.LocationError(
+                "latitude, longitude and timezone must be given together"
+            )
+        return timelapse.location.Location(
+            name=self.name,
+            latitude=self.latitude,
+            longitude=self.longitude,
+            elevation=self.elevation,
+            timezone=self.timezone,
+        )
+
+    # Record the camera's details next to its frames; see
+    # timelapse/metadata.py.
+    def write_metadata(self) -> None:
+        timelapse.metadata.write(
+            timelapse.metadata.base_directory(self.output_directory),
+            self.name,
+            {
+                "camera": self.name,
+                "filenames": self.filename_template(),
+                "location": timelapse.metadata.location_dict(self.location()),
+                "privacy": {
+                    "masks": self.privacy_masks,
+                    "mode": self.privacy_mode,
+                    "masked_at_capture": self.mask_at_capture,
+                },
+            },
+        )
+
+    # Where the frame captured at `when` goes, creating its directory.
+    # Names and directories use the camera's time, which is how the
+    # filter reads them back.
+    def output_path(self, when: float) -> pathlib.Path:
+        local = datetime.datetime.fromtimestamp(when, self.location().tzinfo)
+        basedir = pathlib.Path(local.strftime(self.directory_template()))
+        basedir.mkdir(mode=0o755, parents=True, exist_ok=True)
+        return basedir / self.layout().name(local, self.name)
+
+
+FIELDS = {field.name: field for field in dataclasses.fields(CameraConfig)}
+REQUIRED = [
+    name
+    for name, field in FIELDS.items()
+    if field.default is dataclasses.MISSING
+    and field.default_factory is dataclasses.MISSING
+]
+BACKENDS = ("native", "ffmpeg")
+
+
+# Whether `value` is right for the camera key `key`.
+def valid_value(key: str, value: Any) -> bool:
+    expected = FIELDS[key].type
+    if expected in (float, Optional[float]):
+        return isinstance(value, (int, float)) and not isinstance(value, bool)
+    if expected == int:
+        return isinstance(value, int) and not isinstance(value, bool)
+    if expected == List[str]:
+        return isinstance(value, list) and all(isinstance(v, str) for v in value)
+    if expected == Optional[str]:
+        return isinstance(value, str)
+    return isinstance(value, expected)
+
+
+def check_types(table: Dict[str, Any], where: str) -> List[str]:
+    problems = []
+    for key, value in table.items():
+        if key not in FIELDS:
+            problems.append(f"{where}: unknown key '{key}'")
+        elif not valid_value(key, value):
+            problems.append(f"{where}: '{key}' has the wrong type ({type(value).__name__})")
+    return problems
+
+
+def load_config(path: str) -> List[CameraConfig]:
+    cameras, problems = read_config(path)
+    if problems:
+        raise ConfigError(problems)
+    return cameras
+
+
+# The cameras in a config file and the structural problems with it.
+# Keys with problems are left out, so what's left of each camera can
+# still be checked; cameras missing required keys are left out
+# altogether.  Files that can't be read or have no sensible layout at
+# all raise ConfigError.
+def read_config(path: str) -> Tuple[List[CameraConfig], List[str]]:
+    try:
+        with open(path, "rb") as f:
+            document = tomllib.load(f)
+    except (OSError, tomllib.TOMLDecodeError) as ex:
+        raise ConfigError([f"{path}: {ex}"])
+
+    problems = []
+    for key in document:
+        if key not in ("defaults", "camera"):
+            problems.append(f"{path}: unknown table '{key}'")
+    defaults = document.get("defaults", {})
+    tables = document.get("camera", [])
+    if not isinstance(defaults, dict):
+        raise ConfigError([f"{path}: 'defaults' should be a [defaults] table"])
+    if not isinstance(tables, list):
+        raise ConfigError([f"{path}: cameras should be [[camera]] tables"])
+    problems += check_types(defaults, "[defaults]")
+    cameras = []
+    if not tables:
+        problems.append(f"{path}: no [[camera]] entries")
+    for index, table in enumerate(tables):
+        where = f"camera '{table.get('name', index + 1)}'"
+        problems += check_types(table, where)
+        given = {**defaults, **table}
+        merged = {
+            key: value
+            for key, value in given.items()
+            if key in FIELDS and valid_value(key, value)
+        }
+        missing = [key for key in REQUIRED if key not in given]
+        if missing:
+            problems.append(f"{where}: missing {', '.join(missing)}")
+        if any(key not in merged for key in REQUIRED):
+            continue
+        cameras.append(CameraConfig(**merged))
+    return cameras, problems
+
+
+# Problems that would only show up once capture is running: unreachable
+# looking URLs, unknown cities or timezones, and cameras that would
+# overwrite each other's frames.
+def check_cameras(cameras: List[CameraConfig]) -> List[str]:
+    problems = []
+    names: Dict[str, int] = {}
+    outputs: Dict[str, str] = {}
+    for camera in cameras:
+        where = f"camera '{camera.name}'"
+        names[camera.name] = names.get(camera.name, 0) + 1
+
+        url = urllib.parse.urlsplit(camera.url)
+        try:
+            url.port
+        except ValueError:
+            problems.append(f"{where}: bad port in URL {camera.url}")
+        if url.scheme != "rtsp" or not url.hostname:
+            problems.append(f"{where}: '{camera.url}' is not an rtsp://HOST/PATH URL")
+
+        try:
+            camera.location()
+        except timelapse.location.LocationError as ex:
+            problems.append(f"{where}: {ex}")
+        for rule in camera.schedule:
+            try:
+                timelapse.schedule.parse_rule(rule)
+            except timelapse.schedule.ScheduleError as ex:
+                problems.append(f"{where}: {ex}")
+
+        for mask in camera.privacy_masks:
+            try:
+                timelapse.privacy.parse_polygon(mask)
+            except timelapse.privacy.PrivacyError as ex:
+                problems.append(f"{where}: {ex}")
+        if camera.privacy_mode not in timelapse.privacy.MODES:
+            problems.append(
+                f"{where}: privacy_mode must be one of {', '.join(timelapse.privacy.MODES)}"
+            )
+
+        if camera.interval <= 0:
+            problems.append(f"{where}: interval must be positive")
+        if camera.backend not in BACKENDS:
+            problems.append(f"{where}: backend must be one of {', '.join(BACKENDS)}")
+
+        try:
+            camera.layout()
+        except timelapse.frames.LayoutError as ex:
+            problems.append(f"{where}: {ex}")
+        output = os.path.join(
+            os.path.normpath(camera.directory_template()),
+            camera.filename_template().replace("{camera}", camera.name),
+        )
+        if output in outputs:
+            problems.append(
+                f"{where}: writes the same files as camera '{outputs[output]}' ({output})"
+            )
+        else:
+            outputs[output] = camera.name
+        if not os.path.isabs(camera.output_directory):
+            problems.append(f"{where}: output_directory should be an absolute path")
+
+    for name, count in names.items():
+        if count > 1:
+            problems.append(f"camera name '{name}' is used {count} times")
+    return problems
+
+
+def find_camera(cameras: List[CameraConfig], name: str) -> CameraConfig:
+    for camera in cameras:
+        if camera.name == name:
+            return camera
+    raise ConfigError([f"no camera named '{name}'"])
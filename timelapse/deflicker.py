@@ -0,0 +1,126 @@
+# Evening out the flicker from passing clouds and auto-exposure hunting.
+# Each frame's average colour is measured from a thumbnail and compared
+# with the average over the frames around it; the frame is then scaled
+# towards that smoothed level.  Brightness and white balance are
+# corrected separately, each with a strength from 0 (leave alone) to 1
+# (match the smoothed curve exactly).
+#
+# The window doesn't reach across gaps in capture (nights, outages; see
+# selection.gap_threshold), so the first frame of a morning isn't
+# evened out against the evening before.
+
+import dataclasses
+import os
+from typing import Any, Dict, List, Tuple
+
+from timelapse import frames
+from timelapse import render
+from timelapse import selection
+
+# Width of the thumbnail frames are measured from; plenty for an
+# average and far quicker than the full frame.
+MEASURE_WIDTH = 64
+
+# Frames are never brightened or darkened by more than this factor (a
+# stop), so a genuinely dark frame isn't blown out.
+MAX_GAIN = 2.0
+
+Colour = Tuple[float, float, float]
+
+
+def luminance(colour: Colour) -> float:
+    r, g, b = colour
+    return 0.2126 * r + 0.7152 * g + 0.0722 * b
+
+
+def measure(path: str) -> Colour:
+    import PIL.Image  # type: ignore
+    import PIL.ImageStat  # type: ignore
+
+    with PIL.Image.open(path) as image:
+        image = image.convert("RGB")
+        height = max(1, image.height * MEASURE_WIDTH // image.width)
+        image = image.resize((MEASURE_WIDTH, height))
+    r, g, b = PIL.ImageStat.Stat(image).mean
+    return r, g, b
+
+
+@dataclasses.dataclass
+class Deflicker(render.Step):
+    # Frames either side of each frame to average over.
+    radius: int = 7
+    brightness: float = 1.0
+    white_balance: float = 0.5
+    found: List[frames.Frame] = dataclasses.field(default_factory=list, repr=False)
+    index: Dict[str, int] = dataclasses.field(default_factory=dict, repr=False)
+    # First and last index (inclusive) of the run without gaps each
+    # frame is in.
+    runs: List[Tuple[int, int]] = dataclasses.field(default_factory=list, repr=False)
+    colours: Dict[int, Colour] = dataclasses.field(default_factory=dict, repr=False)
+
+    def __post_init__(self) -> None:
+        if self.radius < 1:
+            raise render.RenderError("deflicker window must be at least 1 frame")
+        for name in ("brightness", "white_balance"):
+            if not 0 <= getattr(self, name) <= 1:
+                raise render.RenderError(f"deflicker {name.replace('_', ' ')} must be 0 to 1")
+
+    def prepare(self, found: List[frames.Frame]) -> None:
+        self.found = found
+        self.index = {frame.path: i for i, frame in enumerate(found)}
+        self.colours = {}
+        self.runs = []
+        threshold = selection.gap_threshold(found)[1]
+        start = 0
+        for i in range(1, len(found) + 1):
+            if i == len(found) or (found[i].time - found[i - 1].time).total_seconds() > threshold:
+                self.runs += [(start, i - 1)] * (i - start)
+                start = i
+
+    def window(self, i: int) -> range:
+        first, last = self.runs[i]
+        return range(max(first, i - self.radius), min(last, i + self.radius) + 1)
+
+    def colour(self, i: int) -> Colour:
+        if i not in self.colours:
+            self.colours[i] = measure(self.found[i].path)
+        return self.colours[i]
+
+    # Correction depends on the frames in each window, so a segment's
+    # key covers every frame its windows reach, not just its own.
+    def key(self, found: List[frames.Frame]) -> Any:
+        indices = [self.index[frame.path] for frame in found]
+        reach = sorted({j for i in indices for j in self.window(i)})
+        files = []
+        for j in reach:
+            info = os.stat(self.found[j].path)
+            files.append([self.found[j].path, info.st_size, info.st_mtime_ns])
+        return [self.radius, self.brightness, self.white_balance, files]
+
+    # Per channel gains for frame i.
+    def gains(self, i: int) -> Colour:
+        colours = [self.colour(j) for j in self.window(i)]
+        smoothed: Colour = tuple(sum(c[k] for c in colours) / len(colours) for k in range(3))  # type: ignore
+        own = self.colour(i)
+        level, target = luminance(own), luminance(smoothed)
+        if level <= 0 or target <= 0:
+            return 1.0, 1.0, 1.0
+        gain = (target / level) ** self.brightness
+        result = []
+        for k in range(3):
+            if own[k] <= 0 or smoothed[k] <= 0:
+                result.append(gain)
+                continue
+            # How far this channel is off the smoothed balance, once
+            # brightness is taken out of it.
+            balance = (smoothed[k] / target) / (own[k] / level)
+            result.append(gain * balance**self.white_balance)
+        result = [min(MAX_GAIN, max(1 / MAX_GAIN, value)) for value in result]
+        return result[0], result[1], result[2]
+
+    def apply(self, image: Any, frame: frames.Frame) -> Any:
+        gains = self.gains(self.index[frame.path])
+        if all(abs(gain - 1) < 0.002 for gain in gains):
+            return image
+        table = [min(255, round(v * gain)) for gain in gains for v in range(256)]
+        return image.convert("RGB").point(table)
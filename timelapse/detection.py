@@ -0,0 +1,241 @@
+# Finding people, vehicles and animals in frames with a small object
+# detection model, run on the CPU with onnxruntime.  Models are
+# expected to be YOLO-style exports (YOLOv5/v8 and friends) taking a
+# square RGB image and returning a box and per-class scores for each
+# candidate; the COCO class names are assumed unless a labels file
+# (one name per line) is given.
+#
+# onnxruntime and numpy are only needed when detection is used:
+#
+#   pip install onnxruntime numpy
+#
+# Detections are kept in the pile's frame index (see timelapse/index.py)
+# for frames in one, and in a .detections.json file in each directory
+# of frames otherwise, so each frame is only run through a given model
+# once.
+
+import dataclasses
+import json
+import os
+import pathlib
+import sys
+from typing import Any, Dict, List, Optional, Set, Tuple
+
+from timelapse import index
+
+COCO_LABELS = [
+    "person", "bicycle", "car", "motorcycle", "airplane", "bus", "train",
+    "truck", "boat", "traffic light", "fire hydrant", "stop sign",
+    "parking meter", "bench", "bird", "cat", "dog", "horse", "sheep", "cow",
+    "elephant", "bear", "zebra", "giraffe", "backpack", "umbrella",
+    "handbag", "tie", "suitcase", "frisbee", "skis", "snowboard",
+    "sports ball", "kite", "baseball bat", "baseball glove", "skateboard",
+    "surfboard", "tennis racket", "bottle", "wine glass", "cup", "fork",
+    "knife", "spoon", "bowl", "banana", "apple", "sandwich", "orange",
+    "broccoli", "carrot", "hot dog", "pizza", "donut", "cake", "chair",
+    "couch", "potted plant", "bed", "dining table", "toilet", "tv",
+    "laptop", "mouse", "remote", "keyboard", "cell phone", "microwave",
+    "oven", "toaster", "sink", "refrigerator", "book", "clock", "vase",
+    "scissors", "teddy bear", "hair drier", "toothbrush",
+]
+
+CACHE_FILENAME = ".detections.json"
+# Candidates below this confidence aren't stored at all; filters ask
+# for their own, higher, thresholds.
+STORE_CONFIDENCE = 0.25
+OVERLAP_THRESHOLD = 0.45
+# Cache files are saved after this many new detections, so an
+# interrupted run doesn't lose them all.
+SAVE_EVERY = 1000
+
+
+class DetectionError(Exception):
+    pass
+
+
+@dataclasses.dataclass
+class Detection:
+    label: str
+    confidence: float
+    # Left, top, right, bottom in pixels of the original frame.
+    box: Tuple[float, float, float, float]
+
+
+def overlap(a: Tuple[float, ...], b: Tuple[float, ...]) -> float:
+    width = min(a[2], b[2]) - max(a[0], b[0])
+    height = min(a[3], b[3]) - max(a[1], b[1])
+    if width <= 0 or height <= 0:
+        return 0.0
+    intersection = width * height
+    union = (a[2] - a[0]) * (a[3] - a[1]) + (b[2] - b[0]) * (b[3] - b[1]) - intersection
+    return intersection / union
+
+
+# Keep the most confident of each group of overlapping boxes with the
+# same label.
+def suppress(candidates: List[Detection]) -> List[Detection]:
+    kept: List[Detection] = []
+    for candidate in sorted(candidates, key=lambda d: -d.confidence):
+        if all(
+            other.label != candidate.label or overlap(other.box, candidate.box) < OVERLAP_THRESHOLD
+            for other in kept
+        ):
+            kept.append(candidate)
+    return kept
+
+
+class Detector:
+    def __init__(self, model: str, labels: Optional[str] = None) -> None:
+        try:
+            import numpy  # type: ignore  # noqa: F401
+            import onnxruntime  # type: ignore
+        except ImportError as ex:
+            raise DetectionError(f"detection needs onnxruntime and numpy ({ex})")
+        try:
+            self.session = onnxruntime.InferenceSession(
+                model, providers=["CPUExecutionProvider"]
+            )
+        except Exception as ex:  # onnxruntime raises its own, unexported types
+            raise DetectionError(f"can't load {model}: {ex}")
+        self.labels = COCO_LABELS
+        if labels is not None:
+            try:
+                self.labels = [line.strip() for line in open(labels) if line.strip()]
+            except OSError as ex:
+                raise DetectionError(f"can't read {labels}: {ex}")
+        model_input = self.session.get_inputs()[0]
+        self.input_name = model_input.name
+        self.size = model_input.shape[2] if isinstance(model_input.shape[2], int) else 640
+        info = os.stat(model)
+        # Identifies the model in the cache, so swapping it re-runs
+        # detection.
+        self.model_id = f"{os.path.basename(model)}:{info.st_size}:{info.st_mtime_ns}"
+
+    def detect(self, path: str) -> List[Detection]:
+        import numpy  # type: ignore
+        import PIL.Image  # type: ignore
+
+        with PIL.Image.open(path) as image:
+            image = image.convert("RGB")
+        # Letterbox into the model's square input.
+        scale = self.size / max(image.width, image.height)
+        resized = image.resize((round(image.width * scale), round(image.height * scale)))
+        square = PIL.Image.new("RGB", (self.size, self.size), (114, 114, 114))
+        pad_x = (self.size - resized.width) // 2
+        pad_y = (self.size - resized.height) // 2
+        square.paste(resized, (pad_x, pad_y))
+        pixels = numpy.asarray(square, dtype=numpy.float32).transpose(2, 0, 1)[None] / 255
+
+        output = self.session.run(None, {self.input_name: pixels})[0][0]
+        # YOLOv8 style outputs are (4 + classes, candidates); YOLOv5
+        # style are (candidates, 5 + classes) with an objectness score.
+        if output.shape[0] < output.shape[1]:
+            output = output.T
+            boxes, scores = output[:, :4], output[:, 4:]
+        else:
+            boxes, scores = output[:, :4], output[:, 5:] * output[:, 4:5]
+        candidates = []
+        classes = scores.argmax(axis=1)
+        for (cx, cy, w, h), index, row in zip(boxes, classes, scores):
+            confidence = float(row[index])
+            if confidence < STORE_CONFIDENCE or index >= len(self.labels):
+                continue
+            box = (
+                (cx - w / 2 - pad_x) / scale,
+                (cy - h / 2 - pad_y) / scale,
+                (cx + w / 2 - pad_x) / scale,
+                (cy + h / 2 - pad_y) / scale,
+            )
+            candidates.append(
+                Detection(self.labels[index], confidence, tuple(float(v) for v in box))  # type: ignore
+            )
+        return suppress(candidates)
+
+
+# Detections for frames, read from and saved to frame indexes or the
+# per-directory cache files.  Frames are looked up by path and re-run
+# if they've changed.
+class DetectionCache:
+    def __init__(self, detector: Detector, indexes: Optional[index.Indexes] = None) -> None:
+        self.detector = detector
+        self.indexes = indexes
+        # The score detections are kept under in an index.
+        self.score = f"detections {detector.model_id}"
+        self.directories: Dict[pathlib.Path, Dict[str, Any]] = {}
+        self.dirty: Set[pathlib.Path] = set()
+        self.unsaved = 0
+
+    def load(self, directory: pathlib.Path) -> Dict[str, Any]:
+        if directory not in self.directories:
+            data: Dict[str, Any] = {}
+            try:
+                data = json.loads((directory / CACHE_FILENAME).read_text())
+            except (OSError, ValueError):
+                pass
+            if data.get("model") != self.detector.model_id:
+                data = {"model": self.detector.model_id, "frames": {}}
+            self.directories[directory] = data
+        return self.directories[directory]
+
+    def detections(self, path: str) -> List[Detection]:
+        if self.indexes is not None:
+            cached = self.indexes.scores(path).get(self.score)
+            if isinstance(cached, str):
+                return decode(json.loads(cached))
+        file = pathlib.Path(path)
+        data = self.load(file.parent)
+        mtime = file.stat().st_mtime_ns
+        entry = data["frames"].get(file.name)
+        if entry is None or entry["mtime_ns"] != mtime:
+            found = [[d.label, d.confidence, *d.box] for d in self.detector.detect(path)]
+            if self.indexes is not None and self.indexes.set_scores(
+                path, {self.score: json.dumps(found)}
+            ):
+                return decode(found)
+            entry = {"mtime_ns": mtime, "detections": found}
+            data["frames"][file.name] = entry
+            self.dirty.add(file.parent)
+            self.unsaved += 1
+            if self.unsaved >= SAVE_EVERY:
+                self.save()
+        return decode(entry["detections"])
+
+    def save(self) -> None:
+        for directory in self.dirty:
+            path = directory / CACHE_FILENAME
+            tmp = directory / f"{CACHE_FILENAME}.tmp"
+            try:
+                tmp.write_text(json.dumps(self.directories[directory]))
+                os.replace(tmp, path)
+            except OSError as ex:
+                print(f"Can't save detections to {path}: {ex}", file=sys.stderr)
+        self.dirty.clear()
+        self.unsaved = 0
+
+
+# Detections as they're saved: label, confidence and box corners.
+def decode(saved: List[List[Any]]) -> List[Detection]:
+    return [Detection(d[0], d[1], tuple(d[2:6])) for d in saved]  # type: ignore
+
+
+# Parse LABEL or LABEL:CONFIDENCE, as given to --with and --without.
+def parse_condition(text: str, default_confidence: float) -> Tuple[str, float]:
+    label, _, confidence = text.rpartition(":")
+    if not label:
+        return text, default_confidence
+    try:
+        return label, float(confidence)
+    except ValueError:
+        raise DetectionError(f"bad confidence in '{text}'")
+
+
+# Check conditions only name labels the detector can report.
+def check_conditions(detector: Detector, conditions: List[Tuple[str, float]]) -> None:
+    for label, _ in conditions:
+        if label not in detector.labels:
+            raise DetectionError(f"the detection model has no label '{label}'")
+
+
+def matches(detections: List[Detection], condition: Tuple[str, float]) -> bool:
+    label, confidence = condition
+    return any(d.label == label and d.confidence >= confidence for d in detections)
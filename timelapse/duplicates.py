@@ -0,0 +1,56 @@
+# Collapsing runs of frames that look the same, like the thousands
+# captured over a lunch break or a rained-out day.  Frames are compared
+# by a perceptual hash (a difference hash: whether each pixel of a tiny
+# grayscale thumbnail is brighter than the one to its right), so noise,
+# compression and small lighting changes don't make frames look
+# different.
+
+import datetime
+from typing import List, Optional, Tuple
+
+from timelapse import frames
+
+HASH_SIZE = 8  # bits per row and rows, so 64 bit hashes
+
+
+def difference_hash(path: str) -> int:
+    import PIL.Image  # type: ignore
+
+    with PIL.Image.open(path) as image:
+        thumbnail = image.convert("L").resize((HASH_SIZE + 1, HASH_SIZE))
+    data = thumbnail.tobytes()
+    bits = 0
+    for y in range(HASH_SIZE):
+        row = data[y * (HASH_SIZE + 1) : (y + 1) * (HASH_SIZE + 1)]
+        for x in range(HASH_SIZE):
+            bits = (bits << 1) | (row[x] > row[x + 1])
+    return bits
+
+
+def distance(a: int, b: int) -> int:
+    return bin(a ^ b).count("1")
+
+
+# Drop frames within `threshold` bits of the last frame kept.  Comparing
+# with the last frame kept rather than the one before means a slow
+# change (shadows moving) still shows up once it adds up.  With a
+# heartbeat, a frame is kept at least that often however little
+# changes, so time still visibly passes.  Returns the frames kept and
+# how many were dropped.  `hashes` go with `found`, one per frame.
+def collapse(
+    found: List[frames.Frame],
+    hashes: List[int],
+    threshold: int,
+    heartbeat: Optional[datetime.timedelta] = None,
+) -> Tuple[List[frames.Frame], int]:
+    kept: List[frames.Frame] = []
+    last_hash = 0
+    for frame, bits in zip(found, hashes):
+        if (
+            not kept
+            or distance(bits, last_hash) > threshold
+            or (heartbeat is not None and frame.time - kept[-1].time >= heartbeat)
+        ):
+            kept.append(frame)
+            last_hash = bits
+    return kept, len(found) - len(kept)
@@ -0,0 +1,295 @@
+# Capture timestamps in frame filenames, and listing piles of frames in
+# capture order.  Frames are named with local time plus its UTC
+# offset, e.g. cam-2021-11-07_013000-0700.png, so they name an exact
+# instant even in the hour repeated when daylight saving time ends.
+# Older names without the offset are still understood, localized to
+# the camera's timezone.
+#
+# Names follow a layout: the strftime template capture writes them
+# with (output_filenames), which is turned around into a pattern for
+# reading them back.  A layout can use %Y, %m, %d, %H, %M, %S, %f
+# (microseconds) and %z (UTC offset), and {camera} for the camera's
+# name, e.g. "{camera}_%Y%m%dT%H%M%S.%f%z.jpg".  Without a layout,
+# any name with a YYYY-MM-DD_HHMMSS timestamp in it is a frame.
+
+import concurrent.futures
+import dataclasses
+import datetime
+import functools
+import operator
+import os
+import re
+from typing import Dict, Iterable, Iterator, List, Optional, Set, Tuple
+
+import pytz
+
+# Look for files with name components YYYY-MM-DD_HHMMSS, optionally
+# followed by a UTC offset (+HHMM/-HHMM) or Z.
+FILENAME_RE = re.compile(
+    r"\D(\d\d\d\d)-(\d\d)-(\d\d)_(\d\d)(\d\d)(\d\d)(Z|[+-]\d\d\d\d)?\D"
+)
+# What each field a layout can use looks like in a filename.  %Y to %M
+# are needed to place a frame in time.
+LAYOUT_FIELDS = {
+    "Y": r"\d\d\d\d",
+    "m": r"\d\d",
+    "d": r"\d\d",
+    "H": r"\d\d",
+    "M": r"\d\d",
+    "S": r"\d\d",
+    "f": r"\d\d\d\d\d\d",
+    "z": r"Z|[+-]\d\d\d\d",
+}
+LAYOUT_REQUIRED = "YmdHM"
+# Directories listed at once when walking a pile.  Listing a directory
+# on a NAS is mostly waiting on the network, so this is well above the
+# number of cores.
+WALK_THREADS = 16
+
+
+# Only a handful of offsets ever show up, so they're made once each.
+@functools.lru_cache(maxsize=None)
+def parse_offset(text: str) -> datetime.timezone:
+    if text == "Z":
+        return datetime.timezone.utc
+    sign = -1 if text[0] == "-" else 1
+    return datetime.timezone(
+        sign * datetime.timedelta(hours=int(text[1:3]), minutes=int(text[3:5]))
+    )
+
+
+# Attach a zone to a wall-clock time.  In the hour repeated when DST
+# ends the time is ambiguous; `hint` (a POSIX timestamp, like the
+# file's mtime) picks whichever reading is closest to it, and without
+# one standard time wins, since a frame captured in the second pass
+# overwrites the first's identically named file.  Times skipped when
+# DST starts are read as if DST hadn't started yet.
+def localize(
+    naive: datetime.datetime, timezone, hint: Optional[float] = None
+) -> datetime.datetime:
+    try:
+        return timezone.localize(naive, is_dst=None)
+    except pytz.AmbiguousTimeError:
+        readings = [timezone.localize(naive, is_dst=d) for d in (True, False)]
+        if hint is None:
+            return readings[1]
+        return min(readings, key=lambda r: abs(r.timestamp() - hint))
+    except pytz.NonExistentTimeError:
+        return timezone.normalize(timezone.localize(naive, is_dst=False))
+
+
+# The instant a filename's wall-clock time and offset (if it has one)
+# name.  Times without an offset are localized to `timezone`; `path`
+# is only looked at (for its mtime) when that's ambiguous.
+def zoned(
+    naive: datetime.datetime, offset: Optional[str], timezone, path: Optional[str]
+) -> datetime.datetime:
+    if offset is not None:
+        return naive.replace(tzinfo=parse_offset(offset)).astimezone(timezone)
+    try:
+        return timezone.localize(naive, is_dst=None)
+    except (pytz.AmbiguousTimeError, pytz.NonExistentTimeError):
+        hint = None
+        if path is not None:
+            try:
+                hint = os.stat(path).st_mtime
+            except OSError:
+                pass
+        return localize(naive, timezone, hint)
+
+
+# The capture instant encoded in a frame filename, or None if the name
+# doesn't have one.
+def frame_time(
+    filename: str, timezone, path: Optional[str] = None
+) -> Optional[datetime.datetime]:
+    match = FILENAME_RE.search(filename)
+    if match is None:
+        return None
+    yy, mm, dd, h, m, s = (int(x) for x in match.groups()[:6])
+    try:
+        naive = datetime.datetime(yy, mm, dd, h, m, s)
+    except ValueError:
+        return None
+    return zoned(naive, match.group(7), timezone, path)
+
+
+class LayoutError(Exception):
+    pass
+
+
+class Layout:
+    def __init__(self, template: str) -> None:
+        self.template = template
+        pattern = ""
+        seen: Set[str] = set()
+        for part in re.findall(r"%.?|\{camera\}|[^%{]+|\{", template):
+            if part == "%%":
+                pattern += "%"
+                continue
+            if part == "{camera}":
+                field, group = "camera", ".+?"
+            elif part.startswith("%"):
+                field = part[1:]
+                if field not in LAYOUT_FIELDS:
+                    raise LayoutError(
+                        f"filename layout '{template}' uses {part}, which can't be read "
+                        "back from names; use %Y, %m, %d, %H, %M, %S, %f and %z"
+                    )
+                group = LAYOUT_FIELDS[field]
+            else:
+                pattern += re.escape(part)
+                continue
+            if field in seen:
+                pattern += f"(?P={field})"
+            else:
+                pattern += f"(?P<{field}>{group})"
+                # Names from before offsets were added still fit.
+                if field == "z":
+                    pattern += "?"
+                seen.add(field)
+        missing = [f"%{field}" for field in LAYOUT_REQUIRED if field not in seen]
+        if missing:
+            raise LayoutError(f"filename layout '{template}' needs {', '.join(missing)}")
+        self.pattern = re.compile(pattern)
+
+    # The filename for a frame captured at `when` (which should know its
+    # timezone, so %z has something to say).
+    def name(self, when: datetime.datetime, camera: str) -> str:
+        return when.strftime(self.template.replace("{camera}", camera.replace("%", "%%")))
+
+    # The capture instant and camera named by a filename, or None if it
+    # doesn't fit the layout.
+    def parse(
+        self, filename: str, timezone, path: Optional[str] = None
+    ) -> Optional[Tuple[datetime.datetime, Optional[str]]]:
+        match = self.pattern.fullmatch(filename)
+        if match is None:
+            return None
+        fields = match.groupdict()
+        try:
+            naive = datetime.datetime(
+                int(fields["Y"]),
+                int(fields["m"]),
+                int(fields["d"]),
+                int(fields["H"]),
+                int(fields["M"]),
+                int(fields.get("S") or 0),
+                int(fields.get("f") or 0),
+            )
+        except ValueError:
+            return None
+        return zoned(naive, fields.get("z"), timezone, path), fields.get("camera")
+
+
+# The capture instant of a frame and the camera it's from (if its name
+# says), or None if it isn't a frame.  Names that don't fit `layout`,
+# like those of frames captured before it was changed, are read as if
+# there was no layout.
+def read_name(
+    filename: str, timezone, path: Optional[str] = None, layout: Optional[Layout] = None
+) -> Optional[Tuple[datetime.datetime, Optional[str]]]:
+    if layout is not None:
+        parsed = layout.parse(filename, timezone, path)
+        if parsed is not None:
+            return parsed
+    when = frame_time(filename, timezone, path)
+    return None if when is None else (when, None)
+
+
+# The name a legacy frame should have once its offset is made explicit,
+# or None if it already has one (or no timestamp at all).  Names that
+# fit `layout` are given its %z, if it has one.
+def migrated_name(
+    filename: str, when: datetime.datetime, layout: Optional[Layout] = None
+) -> Optional[str]:
+    if layout is not None:
+        fits = layout.pattern.fullmatch(filename)
+        if fits is not None:
+            if "z" not in layout.pattern.groupindex or fits.group("z") is not None:
+                return None
+            return layout.name(when, fits.groupdict().get("camera") or "")
+    match = FILENAME_RE.search(filename)
+    if match is None or match.group(7) is not None:
+        return None
+    start, end = match.start() + 1, match.end() - 1
+    return filename[:start] + when.strftime("%Y-%m-%d_%H%M%S%z") + filename[end:]
+
+
+@dataclasses.dataclass(frozen=True, order=True)
+class Frame:
+    time: datetime.datetime
+    path: str
+    # From the filename, when its layout names the camera.
+    camera: Optional[str] = dataclasses.field(default=None, compare=False)
+
+
+# The frames directly in one directory, and the directories in it.
+def list_directory(
+    directory: str, timezone, layout: Optional[Layout] = None
+) -> Tuple[List[Frame], List[str]]:
+    found: List[Frame] = []
+    subdirs: List[str] = []
+    try:
+        entries = list(os.scandir(directory))
+    except OSError:
+        return found, subdirs  # gone since it was listed, or unreadable
+    for entry in entries:
+        if entry.is_dir():
+            if not entry.is_symlink():
+                subdirs.append(entry.path)
+            continue
+        if entry.name.startswith("."):
+            continue  # e.g. a frame capture is still writing
+        parsed = read_name(entry.name, timezone, entry.path, layout)
+        if parsed is not None:
+            found.append(Frame(parsed[0], entry.path, parsed[1]))
+    return found, subdirs
+
+
+# Every frame under basedir, in no particular order.  Each level of
+# directories (the %Y/%m/%d/%H levels of a pile) is listed in parallel.
+def walk(
+    basedir: str, timezone, layout: Optional[Layout] = None, threads: int = WALK_THREADS
+) -> Iterator[Frame]:
+    pool = concurrent.futures.ThreadPoolExecutor(threads)
+    try:
+        level = [basedir]
+        while level:
+            below: List[str] = []
+            for found, subdirs in pool.map(lambda d: list_directory(d, timezone, layout), level):
+                yield from found
+                below += subdirs
+            level = below
+    finally:
+        pool.shutdown(cancel_futures=True)
+
+
+# Frames from several piles in capture order.  The same frame showing
+# up in more than one pile (say, a pile that was copied to a new NAS
+# while capture kept writing to the old one) is only listed once, from
+# the first pile it's in.
+def merge(piles: Iterable[Iterable[Frame]]) -> List[Frame]:
+    found: List[Frame] = []
+    seen: Set[Tuple[datetime.datetime, str]] = set()
+    for pile in piles:
+        for frame in pile:
+            key = (frame.time, frame.path.rpartition(os.sep)[2])
+            if key not in seen:
+                seen.add(key)
+                found.append(frame)
+    # The same order as sorting the Frames themselves, but much quicker
+    # with millions of them.
+    found.sort(key=operator.attrgetter("time", "path"))
+    return found
+
+
+# Every frame under any of basedirs, in capture order, read with each
+# pile's layout in `layouts` if it has one.
+def scan(
+    basedirs: List[str],
+    timezone,
+    layouts: Optional[Dict[str, Optional[Layout]]] = None,
+) -> List[Frame]:
+    layouts = layouts or {}
+    return merge(walk(basedir, timezone, layouts.get(basedir)) for basedir in basedirs)
@@ -0,0 +1,305 @@
+# A persistent index of the frames in a pile, so selecting frames
+# doesn't mean walking millions of files on a NAS and parsing every
+# name each time.  The index is an SQLite database, frames.sqlite, at
+# the top of the pile next to timelapse.json.  It records each frame's
+# path (relative to the top of the pile), camera, capture instant, size
+# and mtime, plus whatever analysis has been worked out for it (quality
+# scores, hashes and activity as numbers, which SQLite keeps as
+# integers or floats as given, and detections as JSON text; see
+# timelapse/quality.py, duplicates.py, activity.py and detection.py),
+# which is thrown away when the file changes.
+#
+# `manage-timelapse.py reindex` creates an index, or brings one up to
+# date with what's on disk (for frames copied in from elsewhere or
+# deleted), and marks it complete; capture then adds frames to it as
+# it writes them.  An index that has never been completed (one whose
+# first reindex was interrupted) isn't trusted to list the pile.
+
+import datetime
+import os
+import pathlib
+import sqlite3
+import sys
+from typing import Any, Dict, Iterator, List, Optional, Tuple
+
+from timelapse import frames
+
+FILENAME = "frames.sqlite"
+REINDEX_BATCH = 1000
+
+SCHEMA = """
+CREATE TABLE IF NOT EXISTS frames (
+    path TEXT PRIMARY KEY,
+    camera TEXT,
+    instant REAL NOT NULL,
+    size INTEGER NOT NULL,
+    mtime_ns INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS frames_by_instant ON frames (instant);
+CREATE TABLE IF NOT EXISTS settings (
+    name TEXT PRIMARY KEY,
+    value
+);
+CREATE TABLE IF NOT EXISTS scores (
+    path TEXT NOT NULL REFERENCES frames (path) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    value NOT NULL,
+    PRIMARY KEY (path, name)
+);
+"""
+
+
+class FrameIndexError(Exception):
+    pass
+
+
+class FrameIndex:
+    # Opens the index of the pile at `base`, creating it if `create` is
+    # set, or only for reading if `readonly` is.
+    def __init__(
+        self, base: pathlib.Path, create: bool = False, readonly: bool = False
+    ) -> None:
+        self.base = base.resolve()
+        self.path = self.base / FILENAME
+        mode = "ro" if readonly else "rwc" if create else "rw"
+        try:
+            # Capture and the filter can have the index open at once;
+            # wait for each other's writes rather than failing.
+            self.db = sqlite3.connect(f"{self.path.as_uri()}?mode={mode}", timeout=60, uri=True)
+            if not readonly:
+                # A rollback journal, not WAL: WAL needs shared memory,
+                # which network filesystems don't provide.  Setting it
+                # also turns back indexes made when WAL was used.
+                self.db.execute("PRAGMA journal_mode = DELETE")
+                self.db.execute("PRAGMA foreign_keys = ON")
+                self.db.executescript(SCHEMA)
+            else:
+                # Connecting doesn't read anything; check it's an index.
+                self.db.execute("SELECT 1 FROM frames LIMIT 1").fetchall()
+        except sqlite3.Error as ex:
+            raise FrameIndexError(f"can't open {self.path}: {ex}")
+
+    def close(self) -> None:
+        self.db.close()
+
+    # Whether a reindex has finished, so the index lists every frame.
+    def complete(self) -> bool:
+        row = self.db.execute("SELECT value FROM settings WHERE name = 'complete'").fetchone()
+        return row is not None and bool(row[0])
+
+    def relative(self, path: str) -> str:
+        return os.path.relpath(os.path.realpath(path), self.base)
+
+    def absolute(self, relative: str) -> str:
+        return os.path.join(self.base, relative)
+
+    # Add or update one frame, e.g. just after capture writes it.
+    def add(self, path: str, when: datetime.datetime, camera: Optional[str]) -> None:
+        info = os.stat(path)
+        with self.db:
+            self.store(
+                self.relative(path), camera, when.timestamp(), info.st_size, info.st_mtime_ns
+            )
+
+    def store(
+        self, relative: str, camera: Optional[str], instant: float, size: int, mtime_ns: int
+    ) -> None:
+        # Scores belong to the file as it was; drop them if it changed.
+        self.db.execute(
+            "DELETE FROM scores WHERE path = ? AND path IN "
+            "(SELECT path FROM frames WHERE path = ? AND (size != ? OR mtime_ns != ?))",
+            (relative, relative, size, mtime_ns),
+        )
+        self.db.execute(
+            "INSERT INTO frames (path, camera, instant, size, mtime_ns) VALUES (?, ?, ?, ?, ?) "
+            "ON CONFLICT (path) DO UPDATE SET camera = excluded.camera, "
+            "instant = excluded.instant, size = excluded.size, mtime_ns = excluded.mtime_ns",
+            (relative, camera, instant, size, mtime_ns),
+        )
+
+    # Follow a frame that's been renamed, keeping its scores.
+    def rename(self, old: str, new: str) -> None:
+        old_relative, new_relative = self.relative(old), self.relative(new)
+        with self.db:
+            self.db.execute(
+                "INSERT INTO frames (path, camera, instant, size, mtime_ns) "
+                "SELECT ?, camera, instant, size, mtime_ns FROM frames WHERE path = ?",
+                (new_relative, old_relative),
+            )
+            self.db.execute(
+                "UPDATE scores SET path = ? WHERE path = ?", (new_relative, old_relative)
+            )
+            self.db.execute("DELETE FROM frames WHERE path = ?", (old_relative,))
+
+    # Bring the index up to date with the frames on disk, read with
+    # `layout` if there is one.  Frames are recorded as from the camera
+    # their name gives, or from `camera`.  Returns how many frames were
+    # added or changed, and how many were removed.
+    def reindex(
+        self, timezone, camera: Optional[str], layout: Optional[frames.Layout] = None
+    ) -> Tuple[int, int]:
+        known: Dict[str, Tuple[int, int]] = {
+            path: (size, mtime_ns)
+            for path, size, mtime_ns in self.db.execute(
+                "SELECT path, size, mtime_ns FROM frames"
+            )
+        }
+        changed = 0
+        # Committed in batches so capture isn't kept waiting while a big
+        # pile is walked.
+        for frame in frames.walk(str(self.base), timezone, layout):
+            relative = self.relative(frame.path)
+            try:
+                info = os.stat(frame.path)
+            except OSError:
+                continue
+            if known.pop(relative, None) != (info.st_size, info.st_mtime_ns):
+                self.store(
+                    relative,
+                    frame.camera or camera,
+                    frame.time.timestamp(),
+                    info.st_size,
+                    info.st_mtime_ns,
+                )
+                changed += 1
+                if changed % REINDEX_BATCH == 0:
+                    self.db.commit()
+        with self.db:
+            self.db.executemany("DELETE FROM frames WHERE path = ?", [(path,) for path in known])
+            self.db.execute("INSERT OR REPLACE INTO settings (name, value) VALUES ('complete', 1)")
+        return changed, len(known)
+
+    # Frames under `under` (a directory inside the pile) captured at or
+    # after start and before end, in capture order.
+    def query(
+        self,
+        timezone,
+        under: Optional[str] = None,
+        start: Optional[datetime.datetime] = None,
+        end: Optional[datetime.datetime] = None,
+    ) -> Iterator[frames.Frame]:
+        conditions = []
+        parameters: List[object] = []
+        if under is not None:
+            prefix = self.relative(under)
+            if prefix != ".":
+                conditions.append("substr(path, 1, ?) = ?")
+                parameters += [len(prefix) + 1, prefix + os.sep]
+        if start is not None:
+            conditions.append("instant >= ?")
+            parameters.append(start.timestamp())
+        if end is not None:
+            conditions.append("instant < ?")
+            parameters.append(end.timestamp())
+        where = f"WHERE {' AND '.join(conditions)}" if conditions else ""
+        for path, camera, instant in self.db.execute(
+            f"SELECT path, camera, instant FROM frames {where} ORDER BY instant", parameters
+        ):
+            when = datetime.datetime.fromtimestamp(instant, datetime.timezone.utc)
+            yield frames.Frame(when.astimezone(timezone), self.absolute(path), camera)
+
+    def scores(self, path: str) -> Dict[str, Any]:
+        return dict(
+            self.db.execute(
+                "SELECT name, value FROM scores WHERE path = ?", (self.relative(path),)
+            )
+        )
+
+    # Save scores for a frame, returning whether they were saved: only
+    # frames in the index can have scores.
+    def set_scores(self, path: str, scores: Dict[str, Any]) -> bool:
+        relative = self.relative(path)
+        with self.db:
+            if not self.db.execute("SELECT 1 FROM frames WHERE path = ?", (relative,)).fetchone():
+                return False
+            self.db.executemany(
+                "INSERT OR REPLACE INTO scores (path, name, value) VALUES (?, ?, ?)",
+                [(relative, name, value) for name, value in scores.items()],
+            )
+        return True
+
+
+# The top of the pile whose index covers `start` (a pile, or a
+# directory inside one), if there is one: `start` or a directory above
+# it.
+def find_base(start: str) -> Optional[pathlib.Path]:
+    directory = pathlib.Path(start).resolve()
+    for candidate in [directory, *directory.parents]:
+        if (candidate / FILENAME).is_file():
+            return candidate
+    return None
+
+
+# Indexes for a set of piles, opened as they're needed, so scores can
+# be looked up and saved for any frame from them.  An index that can't
+# be written to (on a read-only pile, say) is still read; scores
+# worked out for its frames just aren't kept.
+class Indexes:
+    def __init__(self) -> None:
+        self.bases: Dict[str, Optional[pathlib.Path]] = {}
+        self.opened: Dict[pathlib.Path, Optional[FrameIndex]] = {}
+
+    def for_directory(self, directory: str) -> Optional[FrameIndex]:
+        if directory not in self.bases:
+            self.bases[directory] = find_base(directory)
+        base = self.bases[directory]
+        if base is None:
+            return None
+        if base not in self.opened:
+            self.opened[base] = self.open(base)
+        return self.opened[base]
+
+    def open(self, base: pathlib.Path) -> Optional[FrameIndex]:
+        try:
+            return FrameIndex(base)
+        except FrameIndexError:
+            pass
+        try:
+            return FrameIndex(base, readonly=True)
+        except FrameIndexError as ex:
+            print(f"Not using the index of {base}: {ex}", file=sys.stderr)
+            return None
+
+    def for_frame(self, path: str) -> Optional[FrameIndex]:
+        return self.for_directory(os.path.dirname(os.path.abspath(path)))
+
+    def scores(self, path: str) -> Dict[str, Any]:
+        found = self.for_frame(path)
+        return found.scores(path) if found is not None else {}
+
+    def set_scores(self, path: str, scores: Dict[str, Any]) -> bool:
+        found = self.for_frame(path)
+        try:
+            return found is not None and found.set_scores(path, scores)
+        except sqlite3.Error:
+            return False
+
+
+# Every frame under any of basedirs, in capture order, read from their
+# indexes where they have a complete one and by walking the pile (with
+# its layout in `layouts`, see frames.scan) otherwise.
+def scan(
+    basedirs: List[str],
+    timezone,
+    start: Optional[datetime.datetime] = None,
+    end: Optional[datetime.datetime] = None,
+    indexes: Optional[Indexes] = None,
+    layouts: Optional[Dict[str, Optional[frames.Layout]]] = None,
+) -> List[frames.Frame]:
+    indexes = indexes or Indexes()
+    layouts = layouts or {}
+    piles = []
+    for basedir in basedirs:
+        found = indexes.for_directory(os.path.abspath(basedir))
+        if found is not None and not found.complete():
+            print(
+                f"The index of {basedir} is incomplete, walking it instead "
+                "(run manage-timelapse.py reindex to finish it)",
+                file=sys.stderr,
+            )
+            found = None
+        if found is None:
+            piles.append(frames.walk(basedir, timezone, layouts.get(basedir)))
+        else:
+            piles.append(found.query(timezone, basedir, start, end))
+    return frames.merge(piles)
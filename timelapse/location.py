@@ -0,0 +1,155 @@
+# Where a camera is: coordinates, height above sea level and an IANA
+# timezone.  Locations can come from astral's city database for
+# backwards compatibility, but rural sites will want explicit
+# coordinates.
+
+import astral  # type: ignore
+import astral.geocoder  # type: ignore
+
+import dataclasses
+import datetime
+import pytz
+from typing import Dict, Optional, Tuple
+
+from timelapse import solar
+
+
+class LocationError(Exception):
+    pass
+
+
+# Sun events and the elevation of the sun's center at each; dawn and
+# dusk are civil twilight, as in astral.
+SUN_EVENTS = {
+    "dawn": (-6.0, True),
+    "sunrise": (solar.SUNRISE_ELEVATION, True),
+    "sunset": (solar.SUNRISE_ELEVATION, False),
+    "dusk": (-6.0, False),
+}
+
+SunEvents = Dict[str, Optional[datetime.datetime]]
+
+# Sun events already worked out, by place and local date.  Filtering
+# a pile asks for the same day's events for every frame captured that
+# day, so they're worked out once and shared by every Location at the
+# same coordinates, elevation and timezone.
+SUN_CACHE: Dict[Tuple[float, float, float, str, datetime.date], SunEvents] = {}
+
+
+@dataclasses.dataclass
+class Location:
+    name: str
+    latitude: float
+    longitude: float
+    timezone: str
+    elevation: float = 0.0  # metres above sea level
+
+    def __post_init__(self) -> None:
+        if not -90 <= self.latitude <= 90:
+            raise LocationError(f"latitude {self.latitude} is out of range")
+        if not -180 <= self.longitude <= 180:
+            raise LocationError(f"longitude {self.longitude} is out of range")
+        try:
+            self.tzinfo = pytz.timezone(self.timezone)
+        except pytz.UnknownTimeZoneError:
+            raise LocationError(f"unknown timezone '{self.timezone}'")
+
+    # Apparent elevation of the sun in degrees at a moment, or the true
+    # elevation sunrise, sunset and twilight are defined by if not
+    # `refracted`.
+    def sun_elevation(self, when: datetime.datetime, refracted: bool = True) -> float:
+        return solar.sun_position(self.latitude, self.longitude, when, refracted)[0]
+
+    # Azimuth of the sun in degrees clockwise from north.
+    def sun_azimuth(self, when: datetime.datetime) -> float:
+        return solar.sun_position(self.latitude, self.longitude, when)[1]
+
+    def start_of_day(self, day: datetime.date) -> datetime.datetime:
+        return self.tzinfo.localize(datetime.datetime.combine(day, datetime.time()))
+
+    def solar_noon(self, day: datetime.date) -> datetime.datetime:
+        local_noon = self.tzinfo.localize(datetime.datetime.combine(day, datetime.time(12)))
+        return solar.transit(self.longitude, local_noon).astimezone(self.tzinfo)
+
+    # Local time the sun crosses `elevation` degrees on a date, or
+    # None if it doesn't that day.
+    def sun_crossing(
+        self, day: datetime.date, elevation: float, rising: bool
+    ) -> Optional[datetime.datetime]:
+        result = solar.crossing(
+            self.latitude, self.longitude, self.solar_noon(day), elevation, rising
+        )
+        if isinstance(result, str):
+            return None
+        return result.astimezone(self.tzinfo)
+
+    # Local time the sun is at `azimuth` degrees on a date, while it's
+    # above the horizon, or None if it isn't there that day.
+    def sun_at_azimuth(
+        self, day: datetime.date, azimuth: float
+    ) -> Optional[datetime.datetime]:
+        def offset(when: datetime.datetime) -> float:
+            return (self.sun_azimuth(when) - azimuth + 180) % 360 - 180
+
+        step = datetime.timedelta(minutes=10)
+        when = self.start_of_day(day)
+        end = self.start_of_day(day + datetime.timedelta(days=1))
+        before = offset(when)
+        while when < end:
+            after = offset(when + step)
+            # The sun moves a few degrees per step; a bigger jump is the
+            # point opposite the target wrapping around.
+            if before < 0 <= after and after - before < 90:
+                low, high = when, when + step
+                while high - low > datetime.timedelta(seconds=10):
+                    middle = low + (high - low) / 2
+                    if offset(middle) < 0:
+                        low = middle
+                    else:
+                        high = middle
+                if self.sun_elevation(high, refracted=False) > solar.SUNRISE_ELEVATION:
+                    return high
+            when += step
+            before = after
+        return None
+
+    # Local times of dawn, sunrise, noon, sunset and dusk for a date.
+    # When the sun stays up (or stays above civil twilight) all day,
+    # the rising events are the start of the day and the setting events
+    # its end, so "sunrise..sunset" covers all of a polar summer day.
+    # When it never gets that high, the event is None.
+    def sun_events(self, day: datetime.date) -> SunEvents:
+        key = (self.latitude, self.longitude, self.elevation, self.timezone, day)
+        if key in SUN_CACHE:
+            return SUN_CACHE[key]
+        noon = self.solar_noon(day)
+        dip = solar.horizon_dip(self.elevation)
+        events: SunEvents = {"noon": noon}
+        for name, (elevation, rising) in SUN_EVENTS.items():
+            result = solar.crossing(
+                self.latitude, self.longitude, noon, elevation - dip, rising
+            )
+            if result == solar.ALWAYS_ABOVE:
+                if rising:
+                    events[name] = self.start_of_day(day)
+                else:
+                    events[name] = self.start_of_day(day + datetime.timedelta(days=1))
+            elif isinstance(result, datetime.datetime):
+                events[name] = result.astimezone(self.tzinfo)
+            else:
+                events[name] = None
+        SUN_CACHE[key] = events
+        return events
+
+
+def lookup_city(city: str) -> Location:
+    try:
+        info = astral.geocoder.lookup(city, astral.geocoder.database())
+    except KeyError:
+        raise LocationError(f"unknown city '{city}'")
+    return Location(
+        name=info.name,
+        latitude=info.latitude,
+        longitude=info.longitude,
+        timezone=info.timezone,
+    )
@@ -0,0 +1,101 @@
+# Capture writes a small JSON file at the top of each camera's output
+# directory describing the camera, so tools working on the pile of
+# frames later don't have to be told where it was taken.  Cameras can
+# share an output directory, so the file holds an entry per camera:
+# {"cameras": {NAME: {...}}}.  Older files held a single camera's
+# entry at the top level, and are read as such.
+
+import json
+import os
+import pathlib
+import threading
+from typing import Any, Dict, Optional, Tuple
+
+from timelapse import location
+
+FILENAME = "timelapse.json"
+
+# Capture threads for cameras sharing a directory update the same file.
+write_lock = threading.Lock()
+
+
+# The fixed part of an output directory template, i.e. everything
+# before the first component with strftime fields in it.
+def base_directory(template: str) -> pathlib.Path:
+    base = pathlib.Path()
+    for part in pathlib.Path(template).parts:
+        if "%" in part:
+            break
+        base /= part
+    return base
+
+
+def location_dict(where: location.Location) -> Dict[str, Any]:
+    return {
+        "name": where.name,
+        "latitude": where.latitude,
+        "longitude": where.longitude,
+        "elevation": where.elevation,
+        "timezone": where.timezone,
+    }
+
+
+def location_from(data: Dict[str, Any]) -> location.Location:
+    try:
+        return location.Location(**data["location"])
+    except (KeyError, TypeError) as ex:
+        raise location.LocationError(f"bad location in {FILENAME}: {ex}")
+
+
+# The per-camera entries in a metadata file, whichever format it's in.
+def cameras(data: Dict[str, Any]) -> Dict[str, Dict[str, Any]]:
+    if "cameras" in data:
+        return data["cameras"]
+    return {data.get("camera", ""): data}
+
+
+# Write a camera's metadata into directory, keeping the other cameras'
+# entries and leaving the file alone if it already says the same thing.
+def write(directory: pathlib.Path, camera: str, data: Dict[str, Any]) -> None:
+    path = directory / FILENAME
+    with write_lock:
+        entries: Dict[str, Dict[str, Any]] = {}
+        try:
+            entries = cameras(json.loads(path.read_text()))
+        except (OSError, ValueError):
+            pass
+        entries[camera] = data
+        text = json.dumps({"cameras": entries}, indent=2, sort_keys=True) + "\n"
+        try:
+            if path.read_text() == text:
+                return
+        except OSError:
+            pass
+        directory.mkdir(mode=0o755, parents=True, exist_ok=True)
+        tmp = path.with_name(f".{FILENAME}.tmp")
+        tmp.write_text(text)
+        os.replace(tmp, path)
+
+
+# Look for metadata in start or any directory above it, so pointing a
+# tool at one day of a pile still finds the camera's file.  camera
+# picks an entry from a shared file; it can be left out if the file
+# only describes one camera.
+def find(
+    start: pathlib.Path, camera: Optional[str] = None
+) -> Optional[Tuple[pathlib.Path, Dict[str, Any]]]:
+    for directory in [start, *start.resolve().parents]:
+        path = directory / FILENAME
+        if path.is_file():
+            try:
+                entries = cameras(json.loads(path.read_text()))
+            except (OSError, ValueError, AttributeError) as ex:
+                raise location.LocationError(f"can't read {path}: {ex}")
+            if camera is not None:
+                return (path, entries[camera]) if camera in entries else None
+            if len(entries) > 1:
+                raise location.LocationError(
+                    f"{path} describes cameras {', '.join(sorted(entries))}; pick one with --camera"
+                )
+            return path, next(iter(entries.values()))
+    return None
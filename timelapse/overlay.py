@@ -0,0 +1,192 @@
+# Text burned into rendered frames: the capture time, a caption from a
+# template, and a small indicator of the time of day.  Everything is
+# drawn with Pillow, so it works whatever ffmpeg was built with.
+#
+# Caption templates are strftime formats in which {camera} stands for
+# the camera's name, e.g. "{camera}: week %V".
+
+import dataclasses
+import datetime
+import math
+from typing import Any, Dict, List, Optional, Tuple
+
+from timelapse import frames
+from timelapse import location
+from timelapse import render
+
+POSITIONS = ("top-left", "top-right", "bottom-left", "bottom-right")
+INDICATORS = ("none", "sun", "clock")
+DEFAULT_TIMESTAMP = "%Y-%m-%d %H:%M"
+# Tried in order when no font is given; Pillow looks these up in the
+# usual system font directories.
+DEFAULT_FONTS = ("DejaVuSans.ttf", "Arial.ttf")
+
+
+def load_font(path: Optional[str], size: int) -> Any:
+    import PIL.ImageFont  # type: ignore
+
+    if path is not None:
+        try:
+            return PIL.ImageFont.truetype(path, size)
+        except OSError as ex:
+            raise render.RenderError(f"can't load font {path}: {ex}")
+    for name in DEFAULT_FONTS:
+        try:
+            return PIL.ImageFont.truetype(name, size)
+        except OSError:
+            pass
+    return PIL.ImageFont.load_default()
+
+
+@dataclasses.dataclass
+class Overlay(render.Step):
+    where: location.Location
+    camera: str = ""
+    # strftime format for the capture time, or None for no timestamp.
+    timestamp: Optional[str] = DEFAULT_TIMESTAMP
+    caption: Optional[str] = None
+    indicator: str = "none"
+    font: Optional[str] = None
+    # Text height in pixels; by default a 30th of the frame height.
+    font_size: Optional[int] = None
+    position: str = "bottom-left"
+    box: bool = True
+    fonts: Dict[int, Any] = dataclasses.field(default_factory=dict, repr=False)
+    sun_paths: Dict[datetime.date, List[float]] = dataclasses.field(
+        default_factory=dict, repr=False
+    )
+
+    def __post_init__(self) -> None:
+        if self.position not in POSITIONS:
+            raise render.RenderError(f"position must be one of {', '.join(POSITIONS)}")
+        if self.indicator not in INDICATORS:
+            raise render.RenderError(f"indicator must be one of {', '.join(INDICATORS)}")
+
+    def key(self, found: List[frames.Frame]) -> Any:
+        return [
+            self.where.timezone,
+            self.where.latitude,
+            self.where.longitude,
+            self.camera,
+            self.timestamp,
+            self.caption,
+            self.indicator,
+            self.font,
+            self.font_size,
+            self.position,
+            self.box,
+        ]
+
+    def lines(self, when: datetime.datetime) -> List[str]:
+        lines = []
+        if self.timestamp:
+            lines.append(when.strftime(self.timestamp))
+        if self.caption:
+            lines.append(when.strftime(self.caption.replace("{camera}", self.camera)))
+        return lines
+
+    # Sun elevation every half hour through a local day, for the sun
+    # indicator.
+    def sun_path(self, day: datetime.date) -> List[float]:
+        if day not in self.sun_paths:
+            start = self.where.start_of_day(day)
+            self.sun_paths[day] = [
+                self.where.sun_elevation(start + datetime.timedelta(minutes=30 * i))
+                for i in range(49)
+            ]
+        return self.sun_paths[day]
+
+    # A 24 hour dial with one hand pointing at the time of day, midnight
+    # at the bottom and noon at the top.
+    def draw_clock(self, draw: Any, box: Tuple[int, int, int, int], when: datetime.datetime) -> None:
+        left, top, right, bottom = box
+        draw.ellipse(box, outline=(255, 255, 255, 255), width=max(1, (right - left) // 16))
+        cx, cy = (left + right) / 2, (top + bottom) / 2
+        radius = (right - left) / 2
+        hours = when.hour + when.minute / 60
+        angle = math.radians(hours * 15 + 180)
+        draw.line(
+            (cx, cy, cx + 0.8 * radius * math.sin(angle), cy - 0.8 * radius * math.cos(angle)),
+            fill=(255, 255, 255, 255),
+            width=max(1, (right - left) // 12),
+        )
+
+    # The sun's elevation over the day as a curve against the horizon,
+    # with a dot where it is now.
+    def draw_sun(self, draw: Any, box: Tuple[int, int, int, int], when: datetime.datetime) -> None:
+        left, top, right, bottom = box
+        width, height = right - left, bottom - top
+        horizon = top + height / 2
+
+        def point(hours: float, elevation: float) -> Tuple[float, float]:
+            return left + width * hours / 24, horizon - height / 2 * elevation / 90
+
+        path = self.sun_path(when.date())
+        draw.line((left, horizon, right, horizon), fill=(160, 160, 160, 255), width=1)
+        draw.line(
+            [point(i / 2, elevation) for i, elevation in enumerate(path)],
+            fill=(255, 255, 255, 255),
+            width=max(1, height // 16),
+        )
+        x, y = point(when.hour + when.minute / 60, self.where.sun_elevation(when))
+        r = max(2, height // 8)
+        up = y <= horizon
+        draw.ellipse(
+            (x - r, y - r, x + r, y + r),
+            fill=(255, 210, 0, 255) if up else (90, 90, 140, 255),
+        )
+
+    def apply(self, image: Any, frame: frames.Frame) -> Any:
+        import PIL.Image  # type: ignore
+        import PIL.ImageDraw  # type: ignore
+
+        when = frame.time.astimezone(self.where.tzinfo)
+        lines = self.lines(when)
+        if not lines and self.indicator == "none":
+            return image
+        size = self.font_size or max(12, image.height // 30)
+        if size not in self.fonts:
+            self.fonts[size] = load_font(self.font, size)
+        font = self.fonts[size]
+
+        layer = PIL.Image.new("RGBA", image.size, (0, 0, 0, 0))
+        draw = PIL.ImageDraw.Draw(layer)
+        margin = size // 2
+        spacing = size // 4
+        text = "\n".join(lines)
+        if lines:
+            bbox = draw.multiline_textbbox((0, 0), text, font=font, spacing=spacing)
+            text_width, text_height = bbox[2], bbox[3]
+        else:
+            text_width = text_height = 0
+        dial = 0 if self.indicator == "none" else max(2 * size, text_height)
+        dial_width = 2 * dial if self.indicator == "sun" else dial
+        gap = margin if lines and dial else 0
+        width = dial_width + gap + text_width + 2 * margin
+        height = max(dial, text_height) + 2 * margin
+
+        if self.position.endswith("left"):
+            x = margin
+        else:
+            x = image.width - width - margin
+        if self.position.startswith("top"):
+            y = margin
+        else:
+            y = image.height - height - margin
+        if self.box:
+            draw.rectangle((x, y, x + width, y + height), fill=(0, 0, 0, 150))
+        if dial:
+            box = (x + margin, y + margin, x + margin + dial_width, y + margin + dial)
+            if self.indicator == "sun":
+                self.draw_sun(draw, box, when)
+            else:
+                self.draw_clock(draw, box, when)
+        if lines:
+            draw.multiline_text(
+                (x + margin + dial_width + gap, y + margin),
+                text,
+                font=font,
+                fill=(255, 255, 255, 255),
+                spacing=spacing,
+            )
+        return PIL.Image.alpha_composite(image.convert("RGBA"), layer).convert("RGB")
@@ -0,0 +1,111 @@
+# Hiding parts of frames that shouldn't be published: fixed regions
+# like a neighbor's yard or a public sidewalk, given as polygons per
+# camera, and optionally whatever an object detection model finds
+# (people, or faces with a face model; see timelapse/detection.py).
+#
+# A polygon is written as its corners in pixels, "x,y x,y x,y ...",
+# e.g. "0,600 500,600 500,1080 0,1080" for the bottom left corner of a
+# 1080p frame.  Regions are blurred or blacked out.
+#
+# Masks are normally applied when rendering, leaving the frames on disk
+# untouched; a camera with mask_at_capture set applies its polygons to
+# every frame before it's written instead.
+
+import dataclasses
+import io
+from typing import Any, List, Optional, Tuple
+
+from timelapse import detection
+from timelapse import frames
+from timelapse import render
+
+MODES = ("blur", "black")
+
+Polygon = List[Tuple[float, float]]
+
+
+class PrivacyError(Exception):
+    pass
+
+
+def parse_polygon(text: str) -> Polygon:
+    points = []
+    for corner in text.split():
+        x, comma, y = corner.partition(",")
+        try:
+            points.append((float(x), float(y)))
+        except ValueError:
+            raise PrivacyError(f"bad corner '{corner}' in mask '{text}'")
+        if not comma:
+            raise PrivacyError(f"bad corner '{corner}' in mask '{text}'")
+    if len(points) < 3:
+        raise PrivacyError(f"mask '{text}' needs at least 3 corners")
+    return points
+
+
+def hide(
+    image: Any,
+    polygons: List[Polygon],
+    mode: str,
+    boxes: Optional[List[Tuple[float, float, float, float]]] = None,
+) -> Any:
+    import PIL.Image  # type: ignore
+    import PIL.ImageDraw  # type: ignore
+    import PIL.ImageFilter  # type: ignore
+
+    if not polygons and not boxes:
+        return image
+    mask = PIL.Image.new("L", image.size, 0)
+    draw = PIL.ImageDraw.Draw(mask)
+    for polygon in polygons:
+        draw.polygon(polygon, fill=255)
+    for box in boxes or []:
+        draw.rectangle(box, fill=255)
+    image = image.convert("RGB")
+    if mode == "black":
+        cover = PIL.Image.new("RGB", image.size, (0, 0, 0))
+    else:
+        # Strong enough that faces and house numbers can't be made out.
+        cover = image.filter(PIL.ImageFilter.GaussianBlur(max(8, image.width // 60)))
+    image.paste(cover, (0, 0), mask)
+    return image
+
+
+# Mask an encoded frame, returning it re-encoded in the same format.
+def hide_in_frame(frame: bytes, polygons: List[Polygon], mode: str, fmt: str) -> bytes:
+    import PIL.Image  # type: ignore
+
+    with PIL.Image.open(io.BytesIO(frame)) as image:
+        masked = hide(image, polygons, mode)
+    output = io.BytesIO()
+    masked.save(output, format=fmt)
+    return output.getvalue()
+
+
+@dataclasses.dataclass
+class Privacy(render.Step):
+    polygons: List[Polygon]
+    mode: str = "blur"
+    detections: Optional[detection.DetectionCache] = None
+    # What to hide that detection finds, as (label, confidence).
+    labels: List[Tuple[str, float]] = dataclasses.field(default_factory=list)
+
+    def __post_init__(self) -> None:
+        if self.mode not in MODES:
+            raise render.RenderError(f"mask mode must be one of {', '.join(MODES)}")
+
+    def key(self, found: List[frames.Frame]) -> Any:
+        model = self.detections.detector.model_id if self.detections else None
+        return [self.polygons, self.mode, model, self.labels]
+
+    def apply(self, image: Any, frame: frames.Frame) -> Any:
+        boxes = []
+        if self.detections is not None:
+            for item in self.detections.detections(frame.path):
+                if any(detection.matches([item], condition) for condition in self.labels):
+                    boxes.append(item.box)
+        return hide(image, self.polygons, self.mode, boxes)
+
+    def finish(self) -> None:
+        if self.detections is not None:
+            self.detections.save()
@@ -0,0 +1,103 @@
+# Spotting frames that shouldn't be in a video: nearly black ones from
+# the edges of the day, and broken grabs (flat gray or green frames,
+# half-decoded ones where the bottom is a smear of repeated rows, and
+# ones full of compression blocks).
+#
+# Scores are measured from the luma of each frame, mostly from a
+# thumbnail so they're quick to compute:
+#
+#   brightness     mean, 0-255
+#   contrast       standard deviation, 0-255; near 0 for a flat frame
+#   repeated_rows  fraction of full resolution rows identical to the
+#                  row above; real images have sensor noise, smears don't
+#   blockiness     how much bigger steps are across 8 pixel block edges
+#                  than inside blocks; about 1 for a clean frame
+#   sharpness      variance of the Laplacian; low when blurred, but what
+#                  counts as low depends on the scene
+
+import dataclasses
+from typing import List
+
+# Width of the thumbnail most scores are measured from.
+THUMBNAIL_WIDTH = 640
+# Full resolution rows looked at for blockiness.
+BLOCK_ROW_STEP = 4
+
+
+@dataclasses.dataclass
+class Scores:
+    brightness: float
+    contrast: float
+    repeated_rows: float
+    blockiness: float
+    sharpness: float
+
+
+@dataclasses.dataclass
+class Thresholds:
+    min_brightness: float = 15
+    min_contrast: float = 4
+    max_repeated_rows: float = 0.1
+    max_blockiness: float = 2.0
+    min_sharpness: float = 0
+
+    # Why scores fail these thresholds, or [] if they don't.
+    def problems(self, scores: Scores) -> List[str]:
+        problems = []
+        if scores.brightness < self.min_brightness:
+            problems.append(f"too dark (brightness {scores.brightness:.1f} < {self.min_brightness})")
+        if scores.contrast < self.min_contrast:
+            problems.append(f"uniform colour (contrast {scores.contrast:.1f} < {self.min_contrast})")
+        if scores.repeated_rows > self.max_repeated_rows:
+            problems.append(
+                f"smeared (repeated rows {scores.repeated_rows:.2f} > {self.max_repeated_rows})"
+            )
+        if scores.blockiness > self.max_blockiness:
+            problems.append(f"blocky (blockiness {scores.blockiness:.2f} > {self.max_blockiness})")
+        if scores.sharpness < self.min_sharpness:
+            problems.append(f"blurry (sharpness {scores.sharpness:.1f} < {self.min_sharpness})")
+        return problems
+
+
+def repeated_rows(data: bytes, width: int, height: int) -> float:
+    if height < 2:
+        return 0.0
+    repeats = sum(
+        data[(y - 1) * width : y * width] == data[y * width : (y + 1) * width]
+        for y in range(1, height)
+    )
+    return repeats / (height - 1)
+
+
+def blockiness(data: bytes, width: int, height: int) -> float:
+    edges = inside = 0
+    for y in range(0, height, BLOCK_ROW_STEP):
+        row = data[y * width : (y + 1) * width]
+        edges += sum(abs(row[x] - row[x - 1]) for x in range(8, width, 8))
+        inside += sum(abs(row[x] - row[x - 1]) for x in range(4, width, 8))
+    return (edges + 1) / (inside + 1)
+
+
+def analyze(path: str) -> Scores:
+    import PIL.Image  # type: ignore
+    import PIL.ImageFilter  # type: ignore
+    import PIL.ImageStat  # type: ignore
+
+    with PIL.Image.open(path) as image:
+        luma = image.convert("L")
+    data = luma.tobytes()
+    width, height = luma.size
+    thumbnail = luma
+    if width > THUMBNAIL_WIDTH:
+        thumbnail = luma.resize((THUMBNAIL_WIDTH, max(1, height * THUMBNAIL_WIDTH // width)))
+    stat = PIL.ImageStat.Stat(thumbnail)
+    laplacian = thumbnail.filter(
+        PIL.ImageFilter.Kernel((3, 3), [0, 1, 0, 1, -4, 1, 0, 1, 0], scale=1, offset=128)
+    )
+    return Scores(
+        brightness=stat.mean[0],
+        contrast=stat.stddev[0],
+        repeated_rows=repeated_rows(data, width, height),
+        blockiness=blockiness(data, width, height),
+        sharpness=PIL.ImageStat.Stat(laplacian).var[0],
+    )
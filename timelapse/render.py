@@ -0,0 +1,354 @@
+# Turning a list of frames into a video with ffmpeg: a concat list
+# with an explicit duration for every frame, an encode with progress
+# reporting, and an output file that only appears once it's complete.
+#
+# Long projects are encoded a day at a time into cached segments that
+# are then joined without re-encoding, so adding a day of frames (or
+# changing one day's selection) only encodes that day.
+#
+# Frames can also go through processing steps (overlays and the like)
+# on their way to the encoder, in which case they're decoded here and
+# piped to ffmpeg as raw video rather than read by it directly.
+
+import dataclasses
+import datetime
+import hashlib
+import json
+import os
+import pathlib
+import re
+import subprocess
+import sys
+import tempfile
+import threading
+from typing import IO, Any, Callable, Dict, List, Optional
+
+from timelapse import frames
+
+
+class RenderError(Exception):
+    pass
+
+
+@dataclasses.dataclass
+class Preset:
+    description: str
+    codec_args: List[str]
+    fps: float = 30
+    # Largest output width; frames are scaled down (never up) to fit.
+    max_width: Optional[int] = None
+
+
+PRESETS = {
+    "archive-quality": Preset(
+        "lossless-looking RGB x264, slow to encode",
+        ["-c:v", "libx264rgb", "-preset", "veryslow", "-crf", "21"],
+    ),
+    "web": Preset(
+        "1080p x264 that browsers and phones play",
+        [
+            "-c:v", "libx264", "-preset", "slow", "-crf", "23",
+            "-pix_fmt", "yuv420p", "-movflags", "+faststart",
+        ],
+        max_width=1920,
+    ),
+    "preview": Preset(
+        "small and quick, for checking a selection",
+        ["-c:v", "libx264", "-preset", "veryfast", "-crf", "30", "-pix_fmt", "yuv420p"],
+        max_width=640,
+    ),
+}
+
+
+# Something done to every frame before it's encoded.  prepare() sees
+# the whole selection first, for steps that need to look at more than
+# one frame; key() describes the step's settings for the frames of one
+# segment, so changing them re-encodes it (see segment_key); finish()
+# is called once everything is encoded.
+class Step:
+    def prepare(self, found: List[frames.Frame]) -> None:
+        pass
+
+    def finish(self) -> None:
+        pass
+
+    def key(self, found: List[frames.Frame]) -> Any:
+        raise NotImplementedError
+
+    def apply(self, image: Any, frame: frames.Frame) -> Any:
+        raise NotImplementedError
+
+
+def quote(path: str) -> str:
+    return "'" + path.replace("'", "'\\''") + "'"
+
+
+# An ffconcat list of files, one after another.  Frames listed this way
+# carry no useful timing; encode_args() retimes them.
+def concat_list(paths: List[str]) -> str:
+    lines = ["ffconcat version 1.0"]
+    for path in paths:
+        lines.append(f"file {quote(os.path.abspath(path))}")
+    return "\n".join(lines) + "\n"
+
+
+# Output options for encoding `count` frames.  Each frame is stamped
+# with its number over fps rather than whatever the input said, so
+# exactly that many are written and frame i of a video (and of each
+# day's segment) starts at i / fps, as subtitles and chapters assume.
+def encode_args(preset: Preset, fps: float, count: int) -> List[str]:
+    filters = [f"setpts=N/({fps}*TB)"]
+    if preset.max_width is not None:
+        filters.append(f"scale='min({preset.max_width},iw)':-2")
+    return [
+        "-vf", ",".join(filters), *preset.codec_args, "-r", str(fps), "-frames:v", str(count),
+    ]
+
+
+# Output options for copying streams as they are, keeping the preset's
+# container flags.
+def copy_args(preset: Preset) -> List[str]:
+    copy = ["-c", "copy"]
+    if "-movflags" in preset.codec_args:
+        index = preset.codec_args.index("-movflags")
+        copy += preset.codec_args[index : index + 2]
+    return copy
+
+
+def print_progress(done: int, total: int) -> None:
+    print(f"\rEncoded {done}/{total} frames ({100 * done // max(total, 1)}%)", end="", file=sys.stderr)
+    if done >= total:
+        print(file=sys.stderr)
+
+
+# Run an ffmpeg command writing `output`, reporting progress as it
+# goes.  ffmpeg writes to a temporary file next to `output` that's only
+# renamed into place once the encode succeeds, and (if `total` isn't
+# 0) it wrote exactly `total` frames.  `feed`, if given, writes
+# ffmpeg's standard input.
+def run_ffmpeg(
+    inputs: List[str],
+    encode: List[str],
+    output: pathlib.Path,
+    total: int,
+    progress: Callable[[int, int], None] = print_progress,
+    feed: Optional[Callable[[IO[bytes]], None]] = None,
+) -> None:
+    tmp = output.with_name(f".{output.stem}.tmp{output.suffix}")
+    command = [
+        "ffmpeg", "-y", "-loglevel", "error", "-nostats", "-progress", "pipe:1",
+        *inputs, *encode, str(tmp),
+    ]
+    try:
+        process = subprocess.Popen(
+            command,
+            stdin=subprocess.DEVNULL if feed is None else subprocess.PIPE,
+            stdout=subprocess.PIPE,
+        )
+    except OSError as ex:
+        raise RenderError(f"can't run ffmpeg: {ex}")
+    assert process.stdout is not None
+    done = 0
+    written = 0
+
+    # Progress is read on its own thread so a long feed can't fill the
+    # pipe and stall ffmpeg.
+    def read_progress() -> None:
+        nonlocal done, written
+        assert process.stdout is not None
+        for line in process.stdout:
+            key, _, value = line.decode(errors="replace").strip().partition("=")
+            if key == "frame" and value.isdigit():
+                written = int(value)
+                if written > done:
+                    done = min(written, total)
+                    progress(done, total)
+
+    reader = threading.Thread(target=read_progress, daemon=True)
+    reader.start()
+    if feed is not None:
+        assert process.stdin is not None
+        try:
+            feed(process.stdin)
+            process.stdin.close()
+        except BrokenPipeError:
+            pass  # ffmpeg gave up; its return code says why
+        except BaseException:
+            process.kill()
+            process.wait()
+            tmp.unlink(missing_ok=True)
+            raise
+    returncode = process.wait()
+    reader.join()
+    if returncode != 0:
+        tmp.unlink(missing_ok=True)
+        raise RenderError(f"ffmpeg failed with return code {returncode}")
+    if total and written != total:
+        tmp.unlink(missing_ok=True)
+        raise RenderError(f"ffmpeg wrote {written} frames of {total} to {output}")
+    if done < total:
+        progress(total, total)
+    os.replace(tmp, output)
+
+
+# Decode each frame, run it through `steps` and write it to `pipe` as
+# raw RGB.  Frames are all made the size of the first one.
+def feed_frames(
+    found: List[frames.Frame], steps: List[Step], size: Any, pipe: IO[bytes]
+) -> None:
+    import PIL.Image  # type: ignore
+
+    for frame in found:
+        with PIL.Image.open(frame.path) as image:
+            image = image.convert("RGB")
+        # Steps work in the frame's own pixels (masks and detections
+        # are given in them), so resizing comes last.
+        for step in steps:
+            image = step.apply(image, frame)
+        if image.size != size:
+            image = image.resize(size)
+        pipe.write(image.convert("RGB").tobytes())
+
+
+def encode(
+    found: List[frames.Frame],
+    output: pathlib.Path,
+    preset: Preset,
+    fps: float,
+    steps: List[Step],
+    progress: Callable[[int, int], None] = print_progress,
+) -> None:
+    if steps:
+        import PIL.Image  # type: ignore
+
+        with PIL.Image.open(found[0].path) as first:
+            size = first.size
+        run_ffmpeg(
+            [
+                "-f", "rawvideo", "-pix_fmt", "rgb24",
+                "-s", f"{size[0]}x{size[1]}", "-framerate", str(fps), "-i", "-",
+            ],
+            encode_args(preset, fps, len(found)),
+            output,
+            len(found),
+            progress,
+            lambda pipe: feed_frames(found, steps, size, pipe),
+        )
+        return
+    with tempfile.NamedTemporaryFile("w", suffix=".ffconcat") as concat:
+        concat.write(concat_list([frame.path for frame in found]))
+        concat.flush()
+        run_ffmpeg(
+            ["-f", "concat", "-safe", "0", "-i", concat.name],
+            encode_args(preset, fps, len(found)),
+            output,
+            len(found),
+            progress,
+        )
+
+
+def render(
+    found: List[frames.Frame],
+    output: pathlib.Path,
+    preset: Preset,
+    fps: Optional[float] = None,
+    steps: Optional[List[Step]] = None,
+    progress: Callable[[int, int], None] = print_progress,
+) -> None:
+    if not found:
+        raise RenderError("no frames to render")
+    steps = steps or []
+    for step in steps:
+        step.prepare(found)
+    encode(found, output, preset, fps or preset.fps, steps, progress)
+    for step in steps:
+        step.finish()
+
+
+# Bump to throw away every cached segment, e.g. when the way frames are
+# fed to the encoder changes.
+SEGMENT_VERSION = 3
+
+SEGMENT_RE = re.compile(r"^\d\d\d\d-\d\d-\d\d-[0-9a-f]{20}\.")
+
+
+# A segment is reused only if it was encoded from the same files (as
+# far as their size and mtime tell) with the same settings.
+def segment_key(
+    found: List[frames.Frame], preset: Preset, fps: float, steps: List[Step]
+) -> str:
+    files = []
+    for frame in found:
+        info = os.stat(frame.path)
+        files.append([os.path.abspath(frame.path), info.st_size, info.st_mtime_ns])
+    settings = {
+        "version": SEGMENT_VERSION,
+        "codec_args": preset.codec_args,
+        "max_width": preset.max_width,
+        "fps": fps,
+        "files": files,
+        "steps": [[type(step).__name__, step.key(found)] for step in steps],
+    }
+    text = json.dumps(settings, sort_keys=True)
+    return hashlib.sha256(text.encode()).hexdigest()[:20]
+
+
+# Render `found` into `output` one local day at a time.  Each day's
+# segment lives in a directory for the output (by its full path, so
+# videos with the same name elsewhere don't clash) and preset inside
+# cache_dir (which can be shared between videos), under a name made
+# from the day and segment_key, so days whose selection hasn't changed
+# are joined as they are.  Segments in that directory that this render
+# doesn't use are removed.
+def render_segments(
+    found: List[frames.Frame],
+    output: pathlib.Path,
+    preset: Preset,
+    cache_dir: pathlib.Path,
+    timezone,
+    fps: Optional[float] = None,
+    steps: Optional[List[Step]] = None,
+    progress: Callable[[int, int], None] = print_progress,
+) -> None:
+    if not found:
+        raise RenderError("no frames to render")
+    fps = fps or preset.fps
+    steps = steps or []
+    for step in steps:
+        step.prepare(found)
+    days: Dict[datetime.date, List[frames.Frame]] = {}
+    for frame in found:
+        days.setdefault(frame.time.astimezone(timezone).date(), []).append(frame)
+    preset_name = next((name for name, p in PRESETS.items() if p == preset), "custom")
+    output_key = hashlib.sha256(str(output.resolve()).encode()).hexdigest()[:12]
+    segment_dir = cache_dir / f"{output.name}-{output_key}-{preset_name}"
+    segment_dir.mkdir(parents=True, exist_ok=True)
+    segments = []
+    for day in sorted(days):
+        key = segment_key(days[day], preset, fps, steps)
+        segment = segment_dir / f"{day}-{key}{output.suffix}"
+        if segment.exists():
+            print(f"{day}: {len(days[day])} frames, cached", file=sys.stderr)
+        else:
+            print(f"{day}: encoding {len(days[day])} frames", file=sys.stderr)
+            encode(days[day], segment, preset, fps, steps, progress)
+        segments.append(segment)
+    for step in steps:
+        step.finish()
+
+    print(f"Joining {len(segments)} segments", file=sys.stderr)
+    with tempfile.NamedTemporaryFile("w", suffix=".ffconcat") as concat:
+        concat.write(concat_list([str(segment.resolve()) for segment in segments]))
+        concat.flush()
+        run_ffmpeg(
+            ["-f", "concat", "-safe", "0", "-i", concat.name],
+            copy_args(preset),
+            output,
+            len(found),
+            progress,
+        )
+
+    keep = set(segments)
+    for path in segment_dir.iterdir():
+        if path not in keep and SEGMENT_RE.match(path.name):
+            path.unlink()
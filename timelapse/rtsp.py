@@ -0,0 +1,496 @@
+# A minimal RTSP client: just enough of RFC 2326 to DESCRIBE a camera,
+# SETUP its video track with TCP-interleaved transport and PLAY it,
+# plus depacketization of H.264 (RFC 6184) and H.265 (RFC 7798) RTP
+# payloads into whole access units.  Also reads and writes the simple
+# recording format replayed by rtsp-replay-server.py.
+
+import base64
+import dataclasses
+import hashlib
+import os
+import socket
+import struct
+import time
+import urllib.parse
+from typing import BinaryIO, Dict, Iterator, List, Optional, Tuple
+
+
+class RtspError(Exception):
+    pass
+
+
+ANNEXB_START_CODE = b"\x00\x00\x00\x01"
+
+
+@dataclasses.dataclass
+class VideoTrack:
+    codec: str  # "h264" or "hevc", named as ffmpeg/PyAV name them
+    payload_type: int
+    clock_rate: int
+    control: str
+    parameter_sets: List[bytes]
+
+
+@dataclasses.dataclass
+class AccessUnit:
+    timestamp: int
+    nals: List[bytes]
+    keyframe: bool
+
+    def annexb(self) -> bytes:
+        return b"".join(ANNEXB_START_CODE + nal for nal in self.nals)
+
+
+@dataclasses.dataclass
+class Response:
+    status: int
+    reason: str
+    headers: Dict[str, str]
+    body: bytes
+
+
+# Find the first video track in an SDP description.  Control URLs are
+# resolved against base_url (Content-Base, or the DESCRIBE URL).
+def parse_sdp(sdp: str, base_url: str) -> VideoTrack:
+    if not base_url.endswith("/"):
+        base_url += "/"
+    in_video = False
+    payload_type: Optional[int] = None
+    codec = ""
+    clock_rate = 90000
+    control = ""
+    fmtp = ""
+    for line in sdp.splitlines():
+        line = line.strip()
+        if line.startswith("m="):
+            if in_video:
+                break
+            fields = line[2:].split()
+            in_video = fields[0] == "video"
+            if in_video:
+                payload_type = int(fields[3])
+        elif not in_video:
+            continue
+        elif line.startswith("a=rtpmap:"):
+            pt, encoding = line[len("a=rtpmap:") :].split(None, 1)
+            if int(pt) == payload_type:
+                name, rate = encoding.split("/")[:2]
+                codec = name.upper()
+                clock_rate = int(rate)
+        elif line.startswith("a=fmtp:"):
+            pt, fmtp = line[len("a=fmtp:") :].split(None, 1)
+        elif line.startswith("a=control:"):
+            control = line[len("a=control:") :]
+
+    if payload_type is None:
+        raise RtspError("no video track in SDP")
+    if codec not in ("H264", "H265"):
+        raise RtspError(f"unsupported video codec {codec or 'unknown'}")
+
+    params: Dict[str, str] = {}
+    for param in fmtp.split(";"):
+        if "=" in param:
+            key, value = param.strip().split("=", 1)
+            params[key.lower()] = value
+    parameter_sets = []
+    if codec == "H264":
+        for encoded in params.get("sprop-parameter-sets", "").split(","):
+            if encoded:
+                parameter_sets.append(base64.b64decode(encoded))
+    else:
+        for key in ("sprop-vps", "sprop-sps", "sprop-pps"):
+            for encoded in params.get(key, "").split(","):
+                if encoded:
+                    parameter_sets.append(base64.b64decode(encoded))
+
+    if control in ("", "*"):
+        control = base_url.rstrip("/")
+    elif not control.startswith("rtsp://"):
+        control = urllib.parse.urljoin(base_url, control)
+    return VideoTrack(
+        codec="h264" if codec == "H264" else "hevc",
+        payload_type=payload_type,
+        clock_rate=clock_rate,
+        control=control,
+        parameter_sets=parameter_sets,
+    )
+
+
+# Split an RTP packet into (marker, payload type, sequence number,
+# timestamp, payload).
+def parse_rtp(packet: bytes) -> Tuple[bool, int, int, int, bytes]:
+    if len(packet) < 12 or packet[0] >> 6 != 2:
+        raise RtspError("not an RTP version 2 packet")
+    marker = bool(packet[1] & 0x80)
+    payload_type = packet[1] & 0x7F
+    seq, timestamp = struct.unpack(">HI", packet[2:8])
+    offset = 12 + 4 * (packet[0] & 0x0F)
+    if packet[0] & 0x10:
+        (extension_words,) = struct.unpack(">H", packet[offset + 2 : offset + 4])
+        offset += 4 + 4 * extension_words
+    end = len(packet)
+    if packet[0] & 0x20:
+        end -= packet[-1]
+    return marker, payload_type, seq, timestamp, packet[offset:end]
+
+
+# Reassembles RTP payloads into access units.  Any access unit that
+# lost a packet along the way is dropped instead of being handed to a
+# decoder, which is where gray and smeared frames come from.
+class Depacketizer:
+    def __init__(self, track: VideoTrack) -> None:
+        self.track = track
+        self.hevc = track.codec == "hevc"
+        self.parameter_sets: Dict[int, bytes] = {}
+        for nal in track.parameter_sets:
+            self.parameter_sets[self.nal_type(nal)] = nal
+        self.nals: List[bytes] = []
+        self.fragment: Optional[bytearray] = None
+        self.timestamp: Optional[int] = None
+        self.last_seq: Optional[int] = None
+        # Set when the current access unit lost data: after a sequence
+        # gap, or fragments whose start never arrived (as when joining
+        # the stream partway through a NAL unit).
+        self.broken = False
+
+    def nal_type(self, nal: bytes) -> int:
+        if self.hevc:
+            return (nal[0] >> 1) & 0x3F
+        return nal[0] & 0x1F
+
+    def is_keyframe_nal(self, nal_type: int) -> bool:
+        if self.hevc:
+            return 16 <= nal_type <= 21  # IRAP pictures
+        return nal_type == 5  # IDR slice
+
+    def is_parameter_set(self, nal_type: int) -> bool:
+        if self.hevc:
+            return nal_type in (32, 33, 34)  # VPS, SPS, PPS
+        return nal_type in (7, 8)  # SPS, PPS
+
+    # Feed one RTP packet; returns an access unit when one completes.
+    def push(self, packet: bytes) -> Optional[AccessUnit]:
+        marker, payload_type, seq, timestamp, payload = parse_rtp(packet)
+        if payload_type != self.track.payload_type or not payload:
+            return None
+
+        gap = self.last_seq is not None and seq != (self.last_seq + 1) & 0xFFFF
+        self.last_seq = seq
+        if gap:
+            self.broken = True
+            self.fragment = None
+
+        completed = None
+        if self.timestamp is not None and timestamp != self.timestamp:
+            # The previous access unit never saw its marker bit.  After
+            # a gap we can't tell which of the two lost data, so both
+            # are dropped.
+            completed = self.finish()
+            self.broken = gap
+        self.timestamp = timestamp
+
+        if self.hevc:
+            self.push_hevc(payload)
+        else:
+            self.push_h264(payload)
+
+        if marker:
+            completed = self.finish() or completed
+        return completed
+
+    def push_h264(self, payload: bytes) -> None:
+        nal_type = payload[0] & 0x1F
+        if 1 <= nal_type <= 23:
+            self.nals.append(payload)
+        elif nal_type == 24:  # STAP-A
+            self.push_aggregate(payload, 1)
+        elif nal_type == 28:  # FU-A
+            fu_header = payload[1]
+            if fu_header & 0x80:
+                header = (payload[0] & 0xE0) | (fu_header & 0x1F)
+                self.fragment = bytearray([header])
+            if self.fragment is None:
+                self.broken = True
+                return
+            self.fragment += payload[2:]
+            if fu_header & 0x40:
+                self.nals.append(bytes(self.fragment))
+                self.fragment = None
+
+    def push_hevc(self, payload: bytes) -> None:
+        nal_type = (payload[0] >> 1) & 0x3F
+        if nal_type == 48:  # aggregation packet
+            self.push_aggregate(payload, 2)
+        elif nal_type == 49:  # fragmentation unit
+            fu_header = payload[2]
+            if fu_header & 0x80:
+                header0 = (payload[0] & 0x81) | ((fu_header & 0x3F) << 1)
+                self.fragment = bytearray([header0, payload[1]])
+            if self.fragment is None:
+                self.broken = True
+                return
+            self.fragment += payload[3:]
+            if fu_header & 0x40:
+                self.nals.append(bytes(self.fragment))
+                self.fragment = None
+        elif nal_type < 48:
+            self.nals.append(payload)
+
+    def push_aggregate(self, payload: bytes, offset: int) -> None:
+        while offset + 2 <= len(payload):
+            (size,) = struct.unpack(">H", payload[offset : offset + 2])
+            offset += 2
+            self.nals.append(payload[offset : offset + size])
+            offset += size
+
+    def finish(self) -> Optional[AccessUnit]:
+        nals, broken = self.nals, self.broken or self.fragment is not None
+        self.nals, self.fragment, self.broken = [], None, False
+        if not nals or broken or self.timestamp is None:
+            return None
+
+        types = [self.nal_type(nal) for nal in nals]
+        for nal_type, nal in zip(types, nals):
+            if self.is_parameter_set(nal_type):
+                self.parameter_sets[nal_type] = nal
+        keyframe = any(self.is_keyframe_nal(t) for t in types)
+        if keyframe and not any(self.is_parameter_set(t) for t in types):
+            nals = [self.parameter_sets[t] for t in sorted(self.parameter_sets)] + nals
+        return AccessUnit(timestamp=self.timestamp, nals=nals, keyframe=keyframe)
+
+
+def md5_hex(text: str) -> str:
+    return hashlib.md5(text.encode("utf-8")).hexdigest()
+
+
+def parse_auth_params(header: str) -> Dict[str, str]:
+    params = {}
+    for part in header.split(" ", 1)[1].split(","):
+        if "=" in part:
+            key, value = part.strip().split("=", 1)
+            params[key.lower()] = value.strip('"')
+    return params
+
+
+class RtspClient:
+    def __init__(self, url: str, timeout: float = 10.0) -> None:
+        parsed = urllib.parse.urlsplit(url)
+        if parsed.scheme != "rtsp":
+            raise RtspError(f"not an rtsp:// URL: {url}")
+        self.host = parsed.hostname or ""
+        self.port = parsed.port or 554
+        self.username = urllib.parse.unquote(parsed.username or "")
+        self.password = urllib.parse.unquote(parsed.password or "")
+        # Credentials never go on the wire inside request URLs.
+        netloc = self.host if parsed.port is None else f"{self.host}:{self.port}"
+        self.url = urllib.parse.urlunsplit(
+            ("rtsp", netloc, parsed.path, parsed.query, "")
+        )
+        self.timeout = timeout
+        self.sock: Optional[socket.socket] = None
+        self.reader: Optional[BinaryIO] = None
+        self.cseq = 0
+        self.session: Optional[str] = None
+        self.session_timeout = 60.0
+        self.authorization: Optional[Tuple[str, Dict[str, str]]] = None
+        # Requests sent with the current digest nonce; servers reject a
+        # count they've seen before as a replay.
+        self.nonce_count = 0
+        self.sdp = ""
+        self.track: Optional[VideoTrack] = None
+        self.channel = 0
+        self.last_keepalive = 0.0
+
+    # Connect, DESCRIBE, SETUP the video track and PLAY it.
+    def open(self) -> VideoTrack:
+        self.sock = socket.create_connection((self.host, self.port), self.timeout)
+        self.reader = self.sock.makefile("rb")
+        response = self.request("DESCRIBE", self.url, {"Accept": "application/sdp"})
+        self.sdp = response.body.decode("utf-8", errors="replace")
+        base = response.headers.get(
+            "content-base", response.headers.get("content-location", self.url)
+        )
+        self.track = parse_sdp(self.sdp, base)
+
+        response = self.request(
+            "SETUP",
+            self.track.control,
+            {"Transport": "RTP/AVP/TCP;unicast;interleaved=0-1"},
+        )
+        session = response.headers.get("session")
+        if session is None:
+            raise RtspError("SETUP response has no Session header")
+        self.session, _, options = session.partition(";")
+        if options.strip().startswith("timeout="):
+            self.session_timeout = float(options.strip()[len("timeout=") :])
+        transport = response.headers.get("transport", "")
+        for option in transport.split(";"):
+            if option.startswith("interleaved="):
+                self.channel = int(option[len("interleaved=") :].split("-")[0])
+
+        self.request("PLAY", self.url, {"Range": "npt=0.000-"})
+        self.last_keepalive = time.monotonic()
+        return self.track
+
+    def close(self) -> None:
+        sock, self.sock = self.sock, None
+        if sock is None:
+            return
+        try:
+            if self.session is not None:
+                self.send("TEARDOWN", self.url, {}, sock)
+            sock.shutdown(socket.SHUT_RDWR)
+        except OSError:
+            pass
+        sock.close()
+
+    def send(
+        self,
+        method: str,
+        url: str,
+        headers: Dict[str, str],
+        sock: Optional[socket.socket] = None,
+    ) -> int:
+        self.cseq += 1
+        lines = [f"{method} {url} RTSP/1.0", f"CSeq: {self.cseq}"]
+        lines.append("User-Agent: rtsp-timelapse-scripts")
+        if self.session is not None:
+            lines.append(f"Session: {self.session}")
+        authorization = self.authorization_header(method, url)
+        if authorization is not None:
+            lines.append(f"Authorization: {authorization}")
+        lines += [f"{key}: {value}" for key, value in headers.items()]
+        (sock or self.sock).sendall(("\r\n".join(lines) + "\r\n\r\n").encode("utf-8"))  # type: ignore
+        return self.cseq
+
+    def authorization_header(self, method: str, url: str) -> Optional[str]:
+        if self.authorization is None:
+            return None
+        scheme, params = self.authorization
+        if scheme == "basic":
+            credentials = f"{self.username}:{self.password}".encode("utf-8")
+            return "Basic " + base64.b64encode(credentials).decode("ascii")
+        ha1 = md5_hex(f"{self.username}:{params['realm']}:{self.password}")
+        ha2 = md5_hex(f"{method}:{url}")
+        nonce = params["nonce"]
+        fields = f'username="{self.username}", realm="{params["realm"]}", nonce="{nonce}", uri="{url}"'
+        if "auth" in params.get("qop", "").split(","):
+            self.nonce_count += 1
+            nc = f"{self.nonce_count:08x}"
+            cnonce = os.urandom(8).hex()
+            response = md5_hex(f"{ha1}:{nonce}:{nc}:{cnonce}:auth:{ha2}")
+            fields += f', qop=auth, nc={nc}, cnonce="{cnonce}"'
+        else:
+            response = md5_hex(f"{ha1}:{nonce}:{ha2}")
+        return f'Digest {fields}, response="{response}"'
+
+    # Send a request and wait for its response, retrying once with
+    # credentials if the camera asks for them.
+    def request(self, method: str, url: str, headers: Dict[str, str]) -> Response:
+        for attempt in range(2):
+            cseq = self.send(method, url, headers)
+            response = self.read_response(cseq)
+            if response.status != 401 or attempt > 0 or not self.username:
+                break
+            challenge = response.headers.get("www-authenticate", "")
+            if challenge.lower().startswith("digest"):
+                self.authorization = ("digest", parse_auth_params(challenge))
+                self.nonce_count = 0
+            else:
+                self.authorization = ("basic", {})
+        if response.status != 200:
+            raise RtspError(f"{method} failed: {response.status} {response.reason}")
+        return response
+
+    def read_response(self, cseq: int) -> Response:
+        while True:
+            item = self.read_item()
+            if isinstance(item, Response) and item.headers.get("cseq") == str(cseq):
+                return item
+
+    # Read either an interleaved (channel, data) frame or an RTSP
+    # response off the connection.
+    def read_item(self):
+        assert self.reader is not None
+        first = self.reader.read(1)
+        if not first:
+            raise RtspError("connection closed")
+        if first == b"$":
+            header = self.reader.read(3)
+            if len(header) < 3:
+                raise RtspError("connection closed")
+            channel, length = header[0], struct.unpack(">H", header[1:3])[0]
+            data = self.reader.read(length)
+            if len(data) < length:
+                raise RtspError("connection closed")
+            return channel, data
+
+        status_line = (first + self.reader.readline()).decode("utf-8", errors="replace")
+        parts = status_line.strip().split(" ", 2)
+        if len(parts) < 2 or not parts[0].startswith("RTSP/"):
+            raise RtspError(f"unexpected data from server: {status_line!r}")
+        headers: Dict[str, str] = {}
+        while True:
+            line = self.reader.readline().decode("utf-8", errors="replace").strip()
+            if not line:
+                break
+            key, _, value = line.partition(":")
+            headers[key.strip().lower()] = value.strip()
+        body = self.reader.read(int(headers.get("content-length", "0")))
+        return Response(int(parts[1]), parts[2] if len(parts) > 2 else "", headers, body)
+
+    # Yield RTP packets for the video track, sending keepalives so the
+    # server doesn't expire the session.
+    def packets(self) -> Iterator[bytes]:
+        while self.sock is not None:
+            if time.monotonic() - self.last_keepalive > self.session_timeout / 2:
+                self.send("OPTIONS", self.url, {})
+                self.last_keepalive = time.monotonic()
+            item = self.read_item()
+            if isinstance(item, tuple) and item[0] == self.channel:
+                yield item[1]
+
+    def access_units(self) -> Iterator[AccessUnit]:
+        assert self.track is not None
+        depacketizer = Depacketizer(self.track)
+        for packet in self.packets():
+            access_unit = depacketizer.push(packet)
+            if access_unit is not None:
+                yield access_unit
+
+
+# Recordings are the DESCRIBE response's SDP followed by the raw RTP
+# packets of the video track, each tagged with its offset in seconds
+# from the start of the recording.
+RECORDING_MAGIC = b"RTSPREC1"
+
+
+def record(client: RtspClient, output: BinaryIO, seconds: float) -> int:
+    client.open()
+    sdp = client.sdp.encode("utf-8")
+    output.write(RECORDING_MAGIC + struct.pack(">I", len(sdp)) + sdp)
+    start = time.monotonic()
+    count = 0
+    for packet in client.packets():
+        offset = time.monotonic() - start
+        if offset > seconds:
+            break
+        output.write(struct.pack(">dH", offset, len(packet)) + packet)
+        count += 1
+    client.close()
+    return count
+
+
+def read_recording(recording: BinaryIO) -> Tuple[str, List[Tuple[float, bytes]]]:
+    if recording.read(len(RECORDING_MAGIC)) != RECORDING_MAGIC:
+        raise RtspError("not an RTSP recording")
+    (length,) = struct.unpack(">I", recording.read(4))
+    sdp = recording.read(length).decode("utf-8")
+    packets = []
+    while True:
+        header = recording.read(10)
+        if len(header) < 10:
+            break
+        offset, size = struct.unpack(">dH", header)
+        packets.append((offset, recording.read(size)))
+    return sdp, packets
@@ -0,0 +1,296 @@
+# Schedules decide which moments are worth a frame, both when
+# capturing and when filtering frames afterwards.  A schedule is a list
+# of rules written as space-separated terms, e.g.
+#
+#   "mon..fri sunrise-15m..15:30"
+#   "sat 2021-05-01..2021-09-30 08:00..12:00"
+#   "exclude ics:/etc/timelapse/holidays.ics"
+#
+# Terms in a rule must all match for the rule to match.  A moment is
+# in the schedule if it matches any include rule (or there are no
+# include rules) and no exclude rule.  Terms are:
+#
+#   mon..fri, sat, mon,wed,fri   days of the week
+#   07:00..15:30                 a daily window; either end can instead
+#                                be a sun event (dawn, sunrise, noon,
+#                                sunset, dusk) with an optional offset
+#                                like +30m or -1h15m, and windows that
+#                                end before they start wrap midnight
+#   2021-07-04, 2021-03-01..2021-06-30
+#                                dates or inclusive date ranges
+#   ics:PATH                     any day with an event in an iCalendar
+#                                file, e.g. a list of holidays
+#   sun>5, sun<-18               the sun's elevation in degrees, with
+#                                refraction; both may be given
+#   daylight, civil, nautical, astronomical
+#                                the sun is up, or it is no darker than
+#                                that kind of twilight (going by its true
+#                                elevation, like sunrise and sunset)
+#   golden-hour, blue-hour, night
+#                                the sun is between -4 and 6 degrees,
+#                                between -6 and -4, or below -18
+
+import dataclasses
+import datetime
+import re
+from typing import Dict, List, Optional, Set, Tuple
+
+from timelapse import location
+
+
+class ScheduleError(Exception):
+    pass
+
+
+WEEKDAYS = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"]
+SUN_EVENTS = ("dawn", "sunrise", "noon", "sunset", "dusk")
+
+DATE_RE = re.compile(r"^\d\d\d\d-\d\d-\d\d$")
+CLOCK_RE = re.compile(r"^(\d?\d):(\d\d)(?::(\d\d))?$")
+ELEVATION_RE = re.compile(r"^sun([<>])(-?\d+(?:\.\d+)?)$")
+ELEVATION_BANDS = {
+    "daylight": (-0.833, 90.0),
+    "civil": (-6.0, 90.0),
+    "nautical": (-12.0, 90.0),
+    "astronomical": (-18.0, 90.0),
+    "golden-hour": (-4.0, 6.0),
+    "blue-hour": (-6.0, -4.0),
+    "night": (-90.0, -18.0),
+}
+SUN_RE = re.compile(r"^(" + "|".join(SUN_EVENTS) + r")(?:([+-])(?:(\d+)h)?(?:(\d+)m)?)?$")
+
+
+# One end of a daily window: a clock time, or a sun event plus offset.
+@dataclasses.dataclass
+class Anchor:
+    clock: Optional[datetime.time] = None
+    event: Optional[str] = None
+    offset: datetime.timedelta = datetime.timedelta()
+
+    @classmethod
+    def parse(cls, text: str) -> "Anchor":
+        match = CLOCK_RE.match(text)
+        if match is not None:
+            hour, minute, second = (int(x or 0) for x in match.groups())
+            try:
+                return cls(clock=datetime.time(hour, minute, second))
+            except ValueError:
+                raise ScheduleError(f"bad time of day '{text}'")
+        match = SUN_RE.match(text)
+        if match is None or (match.group(2) and not (match.group(3) or match.group(4))):
+            raise ScheduleError(f"'{text}' is neither a time of day nor a sun event")
+        event, sign, hours, minutes = match.groups()
+        offset = datetime.timedelta(hours=int(hours or 0), minutes=int(minutes or 0))
+        return cls(event=event, offset=-offset if sign == "-" else offset)
+
+
+@dataclasses.dataclass
+class Rule:
+    text: str
+    exclude: bool = False
+    weekdays: Optional[Set[int]] = None
+    dates: Optional[List[Tuple[datetime.date, datetime.date]]] = None
+    window: Optional[Tuple[Anchor, Anchor]] = None
+    holidays: Optional[Set[datetime.date]] = None
+    # Lower and upper bounds on the sun's apparent elevation, in degrees.
+    elevation: Optional[Tuple[float, float]] = None
+    # The same for its true elevation, from ELEVATION_BANDS.
+    band: Optional[Tuple[float, float]] = None
+
+
+def parse_date(text: str) -> datetime.date:
+    try:
+        return datetime.date.fromisoformat(text)
+    except ValueError:
+        raise ScheduleError(f"bad date '{text}'")
+
+
+def parse_weekdays(text: str) -> Optional[Set[int]]:
+    names = text.split("..")
+    if len(names) == 2 and all(name in WEEKDAYS for name in names):
+        first, last = WEEKDAYS.index(names[0]), WEEKDAYS.index(names[1])
+        return {(first + i) % 7 for i in range((last - first) % 7 + 1)}
+    names = text.split(",")
+    if all(name in WEEKDAYS for name in names):
+        return {WEEKDAYS.index(name) for name in names}
+    return None
+
+
+def parse_rule(text: str) -> Rule:
+    terms = text.split()
+    rule = Rule(text=text)
+    if terms and terms[0] in ("include", "exclude"):
+        rule.exclude = terms.pop(0) == "exclude"
+    if not terms:
+        raise ScheduleError(f"empty schedule rule '{text}'")
+
+    def once(current: object, term: str) -> None:
+        if current is not None:
+            raise ScheduleError(f"more than one {term} in rule '{text}'")
+
+    for term in terms:
+        weekdays = parse_weekdays(term.lower())
+        if weekdays is not None:
+            once(rule.weekdays, "set of weekdays")
+            rule.weekdays = weekdays
+        elif term in ELEVATION_BANDS:
+            low, high = rule.band or (-90.0, 90.0)
+            band = ELEVATION_BANDS[term]
+            rule.band = (max(low, band[0]), min(high, band[1]))
+        elif ELEVATION_RE.match(term):
+            low, high = rule.elevation or (-90.0, 90.0)
+            match = ELEVATION_RE.match(term)
+            assert match is not None
+            degrees = float(match.group(2))
+            band = (degrees, 90.0) if match.group(1) == ">" else (-90.0, degrees)
+            rule.elevation = (max(low, band[0]), min(high, band[1]))
+        elif term.startswith("ics:"):
+            once(rule.holidays, "calendar")
+            rule.holidays = read_ics_dates(term[len("ics:") :])
+        elif all(DATE_RE.match(part) for part in term.replace("..", ",").split(",")):
+            once(rule.dates, "set of dates")
+            rule.dates = []
+            for part in term.split(","):
+                first, _, last = part.partition("..")
+                rule.dates.append((parse_date(first), parse_date(last or first)))
+        elif ".." in term:
+            once(rule.window, "time window")
+            start, end = term.split("..", 1)
+            rule.window = (Anchor.parse(start), Anchor.parse(end))
+        else:
+            raise ScheduleError(f"don't understand '{term}' in rule '{text}'")
+    return rule
+
+
+# Days covered by events in an iCalendar file.  Only what holiday
+# calendars actually use is supported: all-day or timed events, and
+# yearly repeats.
+def read_ics_dates(path: str) -> Set[datetime.date]:
+    try:
+        with open(path, encoding="utf-8") as f:
+            text = f.read()
+    except OSError as ex:
+        raise ScheduleError(f"can't read calendar: {ex}")
+    # Undo line folding: continuation lines start with whitespace.
+    lines = re.sub(r"\r?\n[ \t]", "", text).splitlines()
+
+    days: Set[datetime.date] = set()
+    event: Dict[str, str] = {}
+    for line in lines:
+        if line == "BEGIN:VEVENT":
+            event = {}
+        elif line == "END:VEVENT":
+            days |= event_dates(event, path)
+        elif ":" in line:
+            name, value = line.split(":", 1)
+            event[name.split(";")[0].upper()] = value.strip()
+    return days
+
+
+def ics_date(value: str, path: str) -> datetime.date:
+    try:
+        return datetime.datetime.strptime(value[:8], "%Y%m%d").date()
+    except ValueError:
+        raise ScheduleError(f"{path}: bad date '{value}'")
+
+
+def event_dates(event: Dict[str, str], path: str) -> Set[datetime.date]:
+    if "DTSTART" not in event:
+        return set()
+    start = ics_date(event["DTSTART"], path)
+    end = ics_date(event["DTEND"], path) if "DTEND" in event else start
+    if end > start and "T" not in event.get("DTEND", ""):
+        end -= datetime.timedelta(days=1)  # all-day DTEND is exclusive
+    length = (end - start).days
+    days = {start + datetime.timedelta(days=i) for i in range(length + 1)}
+
+    rrule = event.get("RRULE")
+    if rrule is None:
+        return days
+    parts = dict(part.split("=", 1) for part in rrule.split(";") if "=" in part)
+    if parts.get("FREQ") != "YEARLY" or set(parts) - {"FREQ", "COUNT", "UNTIL"}:
+        raise ScheduleError(f"{path}: unsupported repeating event '{rrule}'")
+    until = ics_date(parts["UNTIL"], path) if "UNTIL" in parts else None
+    count = int(parts.get("COUNT", 100))
+    repeated = set()
+    for year in range(count):
+        for day in days:
+            try:
+                moved = day.replace(year=day.year + year)
+            except ValueError:
+                continue  # February 29th
+            if until is None or moved <= until:
+                repeated.add(moved)
+    return repeated
+
+
+class Schedule:
+    def __init__(self, rules: List[Rule], where: location.Location) -> None:
+        self.rules = rules
+        self.location = where
+        self.timezone = where.tzinfo
+        # Each rule's window on each date, as it's worked out; frames
+        # come many to a day.
+        self.windows: Dict[
+            Tuple[int, datetime.date],
+            Tuple[Optional[datetime.datetime], Optional[datetime.datetime]],
+        ] = {}
+
+    @classmethod
+    def parse(cls, texts: List[str], where: location.Location) -> "Schedule":
+        return cls([parse_rule(text) for text in texts], where)
+
+    # An anchor's moment on a local date, or None for a sun event that
+    # doesn't happen that day (see Location.sun_events).
+    def resolve(self, anchor: Anchor, day: datetime.date) -> Optional[datetime.datetime]:
+        if anchor.clock is not None:
+            return self.timezone.localize(datetime.datetime.combine(day, anchor.clock))
+        assert anchor.event is not None
+        event = self.location.sun_events(day)[anchor.event]
+        if event is None:
+            return None
+        return event + anchor.offset
+
+    def matches(self, rule: Rule, when: datetime.datetime) -> bool:
+        day = when.date()
+        if rule.weekdays is not None and day.weekday() not in rule.weekdays:
+            return False
+        if rule.dates is not None and not any(a <= day <= b for a, b in rule.dates):
+            return False
+        if rule.holidays is not None and day not in rule.holidays:
+            return False
+        if rule.elevation is not None:
+            elevation = self.location.sun_elevation(when)
+            if not rule.elevation[0] < elevation < rule.elevation[1]:
+                return False
+        if rule.band is not None:
+            elevation = self.location.sun_elevation(when, refracted=False)
+            if not rule.band[0] < elevation < rule.band[1]:
+                return False
+        if rule.window is not None:
+            key = (id(rule), day)
+            if key not in self.windows:
+                self.windows[key] = (
+                    self.resolve(rule.window[0], day),
+                    self.resolve(rule.window[1], day),
+                )
+            start, end = self.windows[key]
+            if start is None or end is None:
+                return False
+            if start <= end:
+                return start <= when <= end
+            return when >= start or when <= end
+        return True
+
+    def contains(self, when: datetime.datetime) -> bool:
+        when = when.astimezone(self.timezone)
+        included = False
+        has_includes = False
+        for rule in self.rules:
+            if rule.exclude:
+                if self.matches(rule, when):
+                    return False
+            else:
+                has_includes = True
+                included = included or self.matches(rule, when)
+        return included or not has_includes
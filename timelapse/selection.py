@@ -0,0 +1,243 @@
+# Choosing which frames of a pile go into a video, beyond what the
+# schedule allows: bounds on the capture time, and sampling.
+
+import bisect
+import datetime
+import re
+import statistics
+from typing import Callable, Dict, List, Optional, Tuple
+
+from timelapse import frames
+from timelapse import location
+from timelapse import schedule
+
+
+class SelectionError(Exception):
+    pass
+
+
+# Parse a --from/--to bound: an ISO date or date and time, optionally
+# with a UTC offset; times without one are in the camera's timezone.
+# A bare date as an upper bound means the end of that day.
+def parse_bound(text: str, timezone, upper: bool) -> datetime.datetime:
+    try:
+        if len(text) == 10:
+            day = datetime.date.fromisoformat(text)
+            if upper:
+                day += datetime.timedelta(days=1)
+            naive = datetime.datetime.combine(day, datetime.time())
+        else:
+            naive = datetime.datetime.fromisoformat(text)
+    except ValueError:
+        raise SelectionError(f"bad date/time '{text}'")
+    if naive.tzinfo is not None:
+        return naive
+    return frames.localize(naive, timezone)
+
+
+# Frames captured at or after start and before end.  A bare date as
+# the end bound includes that whole day (see parse_bound).
+def in_range(
+    found: List[frames.Frame],
+    start: Optional[datetime.datetime],
+    end: Optional[datetime.datetime],
+) -> List[frames.Frame]:
+    return [
+        frame
+        for frame in found
+        if (start is None or frame.time >= start) and (end is None or frame.time < end)
+    ]
+
+
+DURATION_RE = re.compile(r"^(?:(\d+)h)?(?:(\d+)m)?(?:(\d+(?:\.\d+)?)s?)?$")
+
+
+# Parse a duration like "10s", "5m", "1h30m" or a plain number of
+# seconds.
+def parse_duration(text: str) -> datetime.timedelta:
+    match = DURATION_RE.match(text)
+    if not text or match is None:
+        raise SelectionError(f"bad duration '{text}'")
+    hours, minutes, seconds = match.groups()
+    duration = datetime.timedelta(
+        hours=int(hours or 0), minutes=int(minutes or 0), seconds=float(seconds or 0)
+    )
+    if duration <= datetime.timedelta():
+        raise SelectionError(f"duration '{text}' must be positive")
+    return duration
+
+
+def sample_every_nth(found: List[frames.Frame], n: int) -> List[frames.Frame]:
+    if n < 1:
+        raise SelectionError(f"can't keep every {n}th frame")
+    return found[::n]
+
+
+# One frame per `spacing` of wall-clock time, on a grid lined up with
+# local midnight (so 5m picks frames nearest :00, :05, ...).  Grid
+# points with no frame within half a spacing are left empty rather than
+# borrowing a far-away frame.
+def sample_spacing(
+    found: List[frames.Frame], spacing: datetime.timedelta, timezone
+) -> List[frames.Frame]:
+    if not found:
+        return []
+    first = found[0].time.astimezone(timezone)
+    origin = frames.localize(datetime.datetime.combine(first.date(), datetime.time()), timezone)
+    step = spacing.total_seconds()
+    best: Dict[int, frames.Frame] = {}
+    for frame in found:
+        offset = (frame.time - origin).total_seconds() / step
+        index = round(offset)
+        current = best.get(index)
+        if current is None or abs(offset - index) < abs(
+            (current.time - origin).total_seconds() / step - index
+        ):
+            best[index] = frame
+    return [best[index] for index in sorted(best)]
+
+
+# Gaps in capture longer than this many typical frame intervals (and
+# at least GAP_MINIMUM seconds) are nights, weekends or outages rather
+# than a slower capture interval.
+GAP_INTERVALS = 20
+GAP_MINIMUM = 600
+
+
+# The typical interval between frames, and the longest interval that
+# isn't a gap in capture, in seconds.
+def gap_threshold(found: List[frames.Frame]) -> Tuple[float, float]:
+    times = [frame.time.timestamp() for frame in found]
+    gaps = [b - a for a, b in zip(times, times[1:])]
+    typical = statistics.median(gaps) if gaps else 0.0
+    return typical, max(GAP_INTERVALS * typical, GAP_MINIMUM)
+
+
+# `count` frames spread evenly along `position`, which gives each
+# frame's place on some non-decreasing scale (time, activity, ...).
+def sample_along(
+    found: List[frames.Frame], position: List[float], count: int
+) -> List[frames.Frame]:
+    chosen: List[frames.Frame] = []
+    for i in range(count):
+        target = position[-1] * i / (count - 1)
+        index = bisect.bisect_left(position, target)
+        if index > 0 and (
+            index == len(position) or target - position[index - 1] < position[index] - target
+        ):
+            index -= 1
+        if not chosen or chosen[-1] is not found[index]:
+            chosen.append(found[index])
+    return chosen
+
+
+# `count` frames spread evenly over the time the pile covers.  Gaps in
+# capture are squeezed down to a single typical frame interval first,
+# so they don't use up the budget.
+def sample_count(found: List[frames.Frame], count: int) -> List[frames.Frame]:
+    if count >= len(found):
+        return list(found)
+    if count <= 1:
+        return found[:count]
+    times = [frame.time.timestamp() for frame in found]
+    gaps = [b - a for a, b in zip(times, times[1:])]
+    typical, threshold = gap_threshold(found)
+    active = [0.0]
+    for gap in gaps:
+        active.append(active[-1] + (gap if gap <= threshold else typical))
+    return sample_along(found, active, count)
+
+
+# Up to `count` frames, spending more of them where there's more going
+# on.  activity[i] scores the change from frame i-1 to frame i (see
+# timelapse/activity.py).  Each stretch between frames counts for its
+# share of capture time (gaps squeezed as in sample_count), scaled by
+# its activity relative to the average; `idle_weight` is what a stretch
+# with no activity at all still counts for, so quiet periods speed up
+# rather than vanish.
+def sample_activity(
+    found: List[frames.Frame], activity: List[float], count: int, idle_weight: float
+) -> List[frames.Frame]:
+    if count >= len(found):
+        return list(found)
+    if count <= 1:
+        return found[:count]
+    times = [frame.time.timestamp() for frame in found]
+    typical, threshold = gap_threshold(found)
+    gaps = [b - a for a, b in zip(times, times[1:])]
+    # A change across a gap is mostly the light, not activity.
+    scores = [0.0 if gap > threshold else score for gap, score in zip(gaps, activity[1:])]
+    average = statistics.fmean(scores)
+    if average <= 0:
+        return sample_count(found, count)
+    position = [0.0]
+    for gap, score in zip(gaps, scores):
+        gap = gap if gap <= threshold else typical
+        weight = idle_weight + (1 - idle_weight) * score / average
+        position.append(position[-1] + gap * weight)
+    return sample_along(found, position, count)
+
+
+# `per_day` evenly spread frames from each local day.
+def sample_per_day(
+    found: List[frames.Frame], per_day: int, timezone
+) -> List[frames.Frame]:
+    days: Dict[datetime.date, List[frames.Frame]] = {}
+    for frame in found:
+        days.setdefault(frame.time.astimezone(timezone).date(), []).append(frame)
+    return [frame for day in sorted(days) for frame in sample_count(days[day], per_day)]
+
+
+SolarTarget = Callable[[datetime.date], Optional[datetime.datetime]]
+
+
+# Where the sun should be in a once-a-day frame: a sun event or clock
+# time as in schedules (noon, sunrise+2h, 12:00), "elevation:30" for
+# the morning the sun reaches 30 degrees ("elevation:30:setting" for
+# the afternoon), or "azimuth:180" for the sun due south.
+def solar_target(text: str, where: location.Location) -> SolarTarget:
+    kind, _, rest = text.partition(":")
+    try:
+        if kind == "elevation":
+            value, _, direction = rest.partition(":")
+            if direction not in ("", "rising", "setting"):
+                raise SelectionError(f"'{direction}' should be rising or setting")
+            elevation = float(value)
+            rising = direction != "setting"
+            return lambda day: where.sun_crossing(day, elevation, rising)
+        if kind == "azimuth":
+            azimuth = float(rest) % 360
+            return lambda day: where.sun_at_azimuth(day, azimuth)
+    except ValueError:
+        raise SelectionError(f"bad number in '{text}'")
+    try:
+        anchor = schedule.Anchor.parse(text)
+    except schedule.ScheduleError as ex:
+        raise SelectionError(str(ex))
+    return lambda day: schedule.Schedule([], where).resolve(anchor, day)
+
+
+# The frame nearest to `target`'s time on each day, as long as it's
+# within `tolerance`.  Also returns the days that had frames, but none
+# close enough (or on which the sun never got there).
+def sample_daily(
+    found: List[frames.Frame],
+    where: location.Location,
+    target: SolarTarget,
+    tolerance: datetime.timedelta,
+) -> Tuple[List[frames.Frame], List[datetime.date]]:
+    days: Dict[datetime.date, List[frames.Frame]] = {}
+    for frame in found:
+        days.setdefault(frame.time.astimezone(where.tzinfo).date(), []).append(frame)
+    chosen = []
+    skipped = []
+    for day in sorted(days):
+        when = target(day)
+        best = None
+        if when is not None:
+            best = min(days[day], key=lambda frame: abs(frame.time - when))
+        if best is None or abs(best.time - when) > tolerance:
+            skipped.append(day)
+        else:
+            chosen.append(best)
+    return chosen, skipped
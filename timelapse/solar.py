@@ -0,0 +1,174 @@
+# Solar position, using NOAA's approximations of Meeus' algorithms.
+# Good to well under a minute for sunrise and sunset, which is plenty
+# for deciding whether a frame is worth keeping.  Unlike astral, times
+# for events that don't happen on a given day (the sun never setting
+# in polar summer, never rising in polar winter) are reported as such
+# rather than raising errors.
+
+import datetime
+import math
+from typing import Tuple, Union
+
+# Returned by crossing() when the sun stays above or below the asked
+# for elevation all day.
+ALWAYS_ABOVE = "always above"
+ALWAYS_BELOW = "always below"
+
+# True elevation of the sun's center at sunrise and sunset, when its
+# upper limb appears on the horizon: its radius plus standard
+# refraction there.
+SUNRISE_ELEVATION = -0.833
+
+
+def julian_century(when: datetime.datetime) -> float:
+    julian_day = when.timestamp() / 86400 + 2440587.5
+    return (julian_day - 2451545) / 36525
+
+
+# The sun's declination (degrees) and the equation of time (minutes)
+# at a given moment.
+def declination_and_equation_of_time(when: datetime.datetime) -> Tuple[float, float]:
+    t = julian_century(when)
+    mean_longitude = math.radians((280.46646 + t * (36000.76983 + t * 0.0003032)) % 360)
+    mean_anomaly = math.radians(357.52911 + t * (35999.05029 - 0.0001537 * t))
+    eccentricity = 0.016708634 - t * (0.000042037 + 0.0000001267 * t)
+    center = (
+        math.sin(mean_anomaly) * (1.914602 - t * (0.004817 + 0.000014 * t))
+        + math.sin(2 * mean_anomaly) * (0.019993 - 0.000101 * t)
+        + math.sin(3 * mean_anomaly) * 0.000289
+    )
+    omega = math.radians(125.04 - 1934.136 * t)
+    apparent_longitude = math.radians(
+        math.degrees(mean_longitude) + center - 0.00569 - 0.00478 * math.sin(omega)
+    )
+    mean_obliquity = 23 + (26 + (21.448 - t * (46.815 + t * (0.00059 - t * 0.001813))) / 60) / 60
+    obliquity = math.radians(mean_obliquity + 0.00256 * math.cos(omega))
+
+    declination = math.asin(math.sin(obliquity) * math.sin(apparent_longitude))
+    y = math.tan(obliquity / 2) ** 2
+    equation_of_time = 4 * math.degrees(
+        y * math.sin(2 * mean_longitude)
+        - 2 * eccentricity * math.sin(mean_anomaly)
+        + 4 * eccentricity * y * math.sin(mean_anomaly) * math.cos(2 * mean_longitude)
+        - 0.5 * y * y * math.sin(4 * mean_longitude)
+        - 1.25 * eccentricity * eccentricity * math.sin(2 * mean_anomaly)
+    )
+    return math.degrees(declination), equation_of_time
+
+
+# Hour angle of the sun in degrees: negative before solar noon,
+# positive after.
+def hour_angle(longitude: float, when: datetime.datetime) -> float:
+    _, equation_of_time = declination_and_equation_of_time(when)
+    utc = when.astimezone(datetime.timezone.utc)
+    minutes = utc.hour * 60 + utc.minute + utc.second / 60 + utc.microsecond / 6e7
+    true_solar_time = (minutes + equation_of_time + 4 * longitude) % 1440
+    return true_solar_time / 4 - 180
+
+
+def clamp_latitude(latitude: float) -> float:
+    return max(-89.9999, min(89.9999, latitude))
+
+
+# Atmospheric refraction in degrees for a true elevation, per NOAA.
+def refraction(elevation: float) -> float:
+    if elevation > 85:
+        return 0.0
+    tan_e = math.tan(math.radians(elevation))
+    if elevation > 5:
+        arcseconds = 58.1 / tan_e - 0.07 / tan_e**3 + 0.000086 / tan_e**5
+    elif elevation > -0.575:
+        arcseconds = 1735 + elevation * (
+            -518.2 + elevation * (103.4 + elevation * (-12.79 + elevation * 0.711))
+        )
+    else:
+        arcseconds = -20.772 / tan_e
+    return arcseconds / 3600
+
+
+# Apparent (refracted) elevation and azimuth of the sun, in degrees,
+# or the true elevation if not `refracted`.  Azimuth is clockwise from
+# north.
+def sun_position(
+    latitude: float, longitude: float, when: datetime.datetime, refracted: bool = True
+) -> Tuple[float, float]:
+    latitude = math.radians(clamp_latitude(latitude))
+    declination, _ = declination_and_equation_of_time(when)
+    declination = math.radians(declination)
+    angle = math.radians(hour_angle(longitude, when))
+
+    cos_zenith = math.sin(latitude) * math.sin(declination) + math.cos(
+        latitude
+    ) * math.cos(declination) * math.cos(angle)
+    zenith = math.acos(max(-1.0, min(1.0, cos_zenith)))
+    elevation = 90 - math.degrees(zenith)
+    azimuth = math.degrees(
+        math.atan2(
+            math.sin(angle),
+            math.cos(angle) * math.sin(latitude) - math.tan(declination) * math.cos(latitude),
+        )
+    )
+    if refracted:
+        elevation += refraction(elevation)
+    return elevation, (azimuth + 180) % 360
+
+
+# Solar noon nearest to `near`.
+def transit(longitude: float, near: datetime.datetime) -> datetime.datetime:
+    when = near.astimezone(datetime.timezone.utc)
+    for _ in range(3):
+        when -= datetime.timedelta(hours=hour_angle(longitude, when) / 15)
+    return when
+
+
+# Half the time the sun spends above `elevation` around solar noon, as
+# an hour angle in degrees, or one of ALWAYS_ABOVE/ALWAYS_BELOW.
+def half_day_angle(
+    latitude: float, elevation: float, when: datetime.datetime
+) -> Union[float, str]:
+    latitude = math.radians(clamp_latitude(latitude))
+    declination = math.radians(declination_and_equation_of_time(when)[0])
+    cos_angle = (
+        math.sin(math.radians(elevation)) - math.sin(latitude) * math.sin(declination)
+    ) / (math.cos(latitude) * math.cos(declination))
+    if cos_angle > 1:
+        return ALWAYS_BELOW
+    if cos_angle < -1:
+        return ALWAYS_ABOVE
+    return math.degrees(math.acos(cos_angle))
+
+
+# When the sun's center crosses the true `elevation` (so
+# SUNRISE_ELEVATION gives sunrise) on its way up (rising) or down,
+# around the solar noon `noon`.
+def crossing(
+    latitude: float,
+    longitude: float,
+    noon: datetime.datetime,
+    elevation: float,
+    rising: bool,
+) -> Union[datetime.datetime, str]:
+    angle = half_day_angle(latitude, elevation, noon)
+    if isinstance(angle, str):
+        return angle
+    sign = -1 if rising else 1
+    when = noon + datetime.timedelta(hours=sign * angle / 15)
+    # Declination and the equation of time drift over the day, so
+    # refine using values at the crossing itself.
+    for _ in range(2):
+        refined = half_day_angle(latitude, elevation, when)
+        if isinstance(refined, str):
+            break
+        error = sign * refined - hour_angle(longitude, when)
+        if error > 180:
+            error -= 360
+        elif error < -180:
+            error += 360
+        when += datetime.timedelta(hours=error / 15)
+    return when
+
+
+# How far the horizon dips below level for an observer `height` metres
+# up, in degrees.
+def horizon_dip(height: float) -> float:
+    return 2.076 * math.sqrt(max(0.0, height)) / 60
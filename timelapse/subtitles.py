@@ -0,0 +1,176 @@
+# Tracks that go alongside a rendered video: subtitles giving the real
+# capture time at each point of the video, and chapters at the start of
+# each day or at events listed in an annotations file.
+#
+# Every frame of a render is shown for exactly 1/fps seconds, so frame
+# i starts at i/fps seconds into the video.
+#
+# Annotations files have one event per line, a date or date and time
+# followed by a title; blank lines and lines starting with # are
+# ignored:
+#
+#   2021-05-03 Excavation starts
+#   2021-05-17 14:00 Foundation pour
+
+import bisect
+import datetime
+import os
+import pathlib
+import re
+from typing import List, Tuple
+
+from timelapse import frames
+from timelapse import render
+from timelapse import selection
+
+DEFAULT_FORMAT = "%Y-%m-%d %H:%M"
+
+Chapter = Tuple[float, str]
+
+
+# (start, end, text) for runs of frames showing the same capture time,
+# once formatted, in seconds of video.
+def cues(
+    found: List[frames.Frame], fps: float, timezone, fmt: str = DEFAULT_FORMAT
+) -> List[Tuple[float, float, str]]:
+    result: List[Tuple[float, float, str]] = []
+    for index, frame in enumerate(found):
+        text = frame.time.astimezone(timezone).strftime(fmt)
+        start, end = index / fps, (index + 1) / fps
+        if result and result[-1][2] == text:
+            result[-1] = (result[-1][0], end, text)
+        else:
+            result.append((start, end, text))
+    return result
+
+
+def timestamp(seconds: float, separator: str) -> str:
+    millis = round(seconds * 1000)
+    hours, millis = divmod(millis, 3600000)
+    minutes, millis = divmod(millis, 60000)
+    seconds, millis = divmod(millis, 1000)
+    return f"{hours:02}:{minutes:02}:{seconds:02}{separator}{millis:03}"
+
+
+def webvtt(entries: List[Tuple[float, float, str]]) -> str:
+    lines = ["WEBVTT", ""]
+    for start, end, text in entries:
+        lines += [f"{timestamp(start, '.')} --> {timestamp(end, '.')}", text, ""]
+    return "\n".join(lines)
+
+
+def srt(entries: List[Tuple[float, float, str]]) -> str:
+    lines = []
+    for number, (start, end, text) in enumerate(entries, 1):
+        lines += [str(number), f"{timestamp(start, ',')} --> {timestamp(end, ',')}", text, ""]
+    return "\n".join(lines)
+
+
+# Write a subtitle file, WebVTT or SRT depending on its extension.
+def write_subtitles(
+    path: pathlib.Path,
+    found: List[frames.Frame],
+    fps: float,
+    timezone,
+    fmt: str = DEFAULT_FORMAT,
+) -> None:
+    entries = cues(found, fps, timezone, fmt)
+    if path.suffix.lower() == ".vtt":
+        text = webvtt(entries)
+    elif path.suffix.lower() == ".srt":
+        text = srt(entries)
+    else:
+        raise render.RenderError(f"{path}: subtitles should be .vtt or .srt")
+    tmp = path.with_name(f".{path.name}.tmp")
+    tmp.write_text(text)
+    os.replace(tmp, path)
+
+
+# A chapter at the first frame of each local day.
+def day_chapters(found: List[frames.Frame], fps: float, timezone) -> List[Chapter]:
+    chapters: List[Chapter] = []
+    last = None
+    for index, frame in enumerate(found):
+        day = frame.time.astimezone(timezone).date()
+        if day != last:
+            chapters.append((index / fps, day.isoformat()))
+            last = day
+    return chapters
+
+
+TIME_RE = re.compile(r"^\d\d?:\d\d(:\d\d)?$")
+
+
+def read_annotations(path: str, timezone) -> List[Tuple[datetime.datetime, str]]:
+    events = []
+    try:
+        with open(path) as f:
+            lines = f.read().splitlines()
+    except OSError as ex:
+        raise render.RenderError(f"can't read {path}: {ex}")
+    for number, line in enumerate(lines, 1):
+        line = line.strip()
+        if not line or line.startswith("#"):
+            continue
+        words = line.split(None, 2)
+        if len(words) > 1 and TIME_RE.match(words[1]):
+            clock = words[1] if words[1][2:3] == ":" else "0" + words[1]
+            when = f"{words[0]}T{clock}"
+            title = words[2] if len(words) > 2 else ""
+        else:
+            when = words[0]
+            title = line[len(when) :].strip()
+        if not title:
+            raise render.RenderError(f"{path}:{number}: expected a date and a title")
+        try:
+            events.append((selection.parse_bound(when, timezone, upper=False), title))
+        except selection.SelectionError as ex:
+            raise render.RenderError(f"{path}:{number}: {ex}")
+    return events
+
+
+# A chapter at the first frame captured at or after each event.  Events
+# after the last frame don't get one.
+def annotation_chapters(
+    found: List[frames.Frame], fps: float, events: List[Tuple[datetime.datetime, str]]
+) -> List[Chapter]:
+    times = [frame.time for frame in found]
+    chapters: List[Chapter] = []
+    for when, title in sorted(events):
+        index = bisect.bisect_left(times, when)
+        if index < len(found):
+            chapters.append((index / fps, title))
+    return chapters
+
+
+def escape_metadata(text: str) -> str:
+    return re.sub(r"([=;#\\\n])", r"\\\1", text)
+
+
+# Put chapters into a rendered video, copying its streams as they are.
+def add_chapters(
+    output: pathlib.Path, chapters: List[Chapter], duration: float, preset: render.Preset
+) -> None:
+    chapters = sorted(chapters)
+    lines = [";FFMETADATA1"]
+    for i, (start, title) in enumerate(chapters):
+        end = chapters[i + 1][0] if i + 1 < len(chapters) else duration
+        lines += [
+            "[CHAPTER]",
+            "TIMEBASE=1/1000",
+            f"START={round(start * 1000)}",
+            f"END={round(end * 1000)}",
+            f"title={escape_metadata(title)}",
+        ]
+    metadata = output.with_name(f".{output.name}.chapters")
+    metadata.write_text("\n".join(lines) + "\n")
+    try:
+        render.run_ffmpeg(
+            ["-i", str(output), "-f", "ffmetadata", "-i", str(metadata)],
+            ["-map", "0", "-map_metadata", "1", "-map_chapters", "1", *render.copy_args(preset)],
+            output,
+            0,
+            lambda done, total: None,
+        )
+    finally:
+        metadata.unlink(missing_ok=True)